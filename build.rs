@@ -0,0 +1,35 @@
+use std::{env, fs, path::Path, process::Command};
+
+fn main() {
+    // Docker builds don't have the git checkout, so the hash can be passed in instead
+    let git_hash = env::var("GIT_HASH").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    println!(
+        "cargo:rustc-env=GIT_HASH={}",
+        git_hash.unwrap_or_else(|| "unknown".to_string())
+    );
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    // HEAD itself only changes on checkout; commits move the branch it points to, which may be
+    // a file of its own or packed in with the others
+    let branch_ref = fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| Some(head.strip_prefix("ref: ")?.trim().to_string()));
+    let watched = [".git/HEAD".to_string()]
+        .into_iter()
+        .chain(branch_ref.map(|branch_ref| format!(".git/{}", branch_ref)))
+        .chain([".git/packed-refs".to_string()]);
+    for path in watched {
+        // Cargo reruns every build for a path that doesn't exist
+        if Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
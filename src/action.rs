@@ -0,0 +1,260 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::sony_commands::SonyCommand;
+
+/// Something the mediator can be told to do from outside, in the form
+/// `{"action": "script", "value": "movie_mode"}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "action", content = "value", rename_all = "snake_case")]
+pub enum Action {
+    SonyCommand(SonyCommand),
+    /// Any remote command by its name in the TV's command list, for commands `SonyCommand`
+    /// doesn't have. Only allowed with `ALLOW_RAW_COMMANDS` set.
+    RawCommand(String),
+    Script(String),
+    OpenApp(String),
+    /// Launch an app by package name on the Android box on the home input
+    AndroidApp(String),
+    /// Wake (`true`) or sleep (`false`) the box on the home input, through Home Assistant
+    BoxPower(bool),
+    /// Reset the box on the home input when it's wedged, whatever the TV is doing. Map a
+    /// topic to it in `COMMAND_TOPICS` to let Home Assistant trigger it.
+    ResetBox,
+    /// Switch to the next input in `CYCLE_INPUTS`
+    CycleInput,
+    /// Turn guest mode on or off; while it's on the mediator doesn't fight input changes
+    GuestMode(bool),
+    ToggleGuestMode,
+    /// Turn the TV off after this many minutes, replacing any running timer; 0 cancels it
+    SleepTimer(u64),
+    /// Turn the TV on (`true`) or off (`false`), unless it's already that way
+    Power(bool),
+    /// Switch the TV to the home input
+    HomeInput,
+    /// Run actions one after another, each as an action object with an optional `delay_ms` to
+    /// wait after the previous step. Starting a macro cancels the rest of any macro already
+    /// running.
+    Macro(Vec<MacroStep>),
+    /// Run the `RECOVER_MACRO` steps to get back to a working setup
+    Recover,
+    /// Run the `ALL_OFF_MACRO` steps to turn everything off
+    AllOff,
+    /// Re-send everything the mediator drives and re-read TV state, in case anything was dropped
+    Resync,
+}
+
+/// Compact form of an action, like `script:movie_mode`, `guest_mode:true`, or `cycle_input`.
+/// Values that aren't valid JSON are taken as strings, so strings that are, like `script:"123"`,
+/// keep their quotes.
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tagged = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", tagged["action"].as_str().unwrap_or_default())?;
+        match &tagged.get("value") {
+            None => Ok(()),
+            Some(Value::String(value)) if serde_json::from_str::<Value>(value).is_err() => {
+                write!(f, ":{}", value)
+            }
+            Some(value) => write!(f, ":{}", value),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = serde_json::Error;
+
+    fn from_str(compact: &str) -> Result<Action, serde_json::Error> {
+        serde_json::from_value(compact_to_json(compact))
+    }
+}
+
+/// The usual JSON form of a compact action
+pub fn compact_to_json(compact: &str) -> Value {
+    match compact.split_once(':') {
+        Some((name, value)) => serde_json::json!({
+            "action": name,
+            "value": serde_json::from_str(value)
+                .unwrap_or_else(|_| Value::String(value.to_string())),
+        }),
+        None => serde_json::json!({ "action": compact }),
+    }
+}
+
+/// An action in a macro, and how long to wait after the previous step before doing it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MacroStep {
+    #[serde(flatten)]
+    pub action: Action,
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+/// Payload of a message on the general command topic, e.g.
+/// `{"action": "open_app", "value": "Netflix", "id": 7}`
+#[derive(Deserialize, Debug)]
+pub struct IncomingCommand {
+    #[serde(flatten)]
+    pub action: Action,
+    /// Echoed back in the ack so the caller can match it up
+    pub id: Option<Value>,
+}
+
+/// Optional payload of a message on a topic from `COMMAND_TOPICS`, which already implies the action
+#[derive(Deserialize, Debug, Default)]
+pub struct CommandRequest {
+    pub id: Option<Value>,
+}
+
+/// Swaps friendly names for Sony commands in a JSON action (and in the steps of a macro) for
+/// the real command names, so callers don't have to know `SonyCommand`'s naming
+pub fn resolve_aliases(action: &mut Value, aliases: &HashMap<String, SonyCommand>) {
+    match action.get("action").and_then(Value::as_str) {
+        Some("sony_command") => {
+            let Some(name) = action.get("value").and_then(Value::as_str) else {
+                return;
+            };
+            if let Some(command) = aliases.get(name) {
+                action["value"] = serde_json::to_value(command).unwrap();
+            }
+        }
+        Some("macro") => {
+            if let Some(steps) = action.get_mut("value").and_then(Value::as_array_mut) {
+                for step in steps {
+                    resolve_aliases(step, aliases);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_commands() {
+        let command: IncomingCommand =
+            serde_json::from_str(r#"{"action": "open_app", "value": "Netflix", "id": 7}"#).unwrap();
+        assert!(matches!(&command.action, Action::OpenApp(app) if app == "Netflix"));
+        assert_eq!(command.id, Some(serde_json::json!(7)));
+
+        let command: IncomingCommand = serde_json::from_str(r#"{"action": "resync"}"#).unwrap();
+        assert!(matches!(command.action, Action::Resync));
+        assert_eq!(command.id, None);
+
+        let command: IncomingCommand = serde_json::from_str(
+            r#"{"action": "macro", "value": [{"action": "home_input", "delay_ms": 500}]}"#,
+        )
+        .unwrap();
+        let Action::Macro(steps) = command.action else {
+            panic!("Not a macro: {:?}", command.action);
+        };
+        assert!(matches!(steps[0].action, Action::HomeInput));
+        assert_eq!(steps[0].delay_ms, 500);
+    }
+
+    #[test]
+    fn resolves_aliases_in_commands_and_macros() {
+        let aliases = HashMap::from([
+            ("telly".to_string(), SonyCommand::Home),
+            ("console".to_string(), SonyCommand::Hdmi2),
+        ]);
+        let resolve = |mut command: Value| {
+            resolve_aliases(&mut command, &aliases);
+            serde_json::from_value::<IncomingCommand>(command)
+                .unwrap()
+                .action
+        };
+
+        let action = resolve(serde_json::json!({"action": "sony_command", "value": "telly"}));
+        assert!(matches!(action, Action::SonyCommand(SonyCommand::Home)));
+
+        // Real command names are left alone
+        let action = resolve(serde_json::json!({"action": "sony_command", "value": "Hdmi1"}));
+        assert!(matches!(action, Action::SonyCommand(SonyCommand::Hdmi1)));
+
+        let action = resolve(serde_json::json!({"action": "macro", "value": [
+            {"action": "sony_command", "value": "console"},
+            {"action": "sony_command", "value": "telly", "delay_ms": 200},
+        ]}));
+        let Action::Macro(steps) = action else {
+            panic!("Not a macro: {:?}", action);
+        };
+        assert!(matches!(
+            steps[0].action,
+            Action::SonyCommand(SonyCommand::Hdmi2)
+        ));
+        assert!(matches!(
+            steps[1].action,
+            Action::SonyCommand(SonyCommand::Home)
+        ));
+        assert_eq!(steps[1].delay_ms, 200);
+
+        let mut command = serde_json::json!({"action": "sony_command", "value": "radio"});
+        resolve_aliases(&mut command, &aliases);
+        assert!(serde_json::from_value::<IncomingCommand>(command).is_err());
+    }
+
+    #[test]
+    fn compact_form_round_trips() {
+        for action in [
+            Action::SonyCommand(SonyCommand::Home),
+            Action::Script("movie_mode".to_string()),
+            Action::Script("123".to_string()),
+            Action::Script("true".to_string()),
+            Action::Script("\"quoted\"".to_string()),
+            Action::Script("with:colon".to_string()),
+            Action::Script(String::new()),
+            Action::OpenApp("Prime Video".to_string()),
+            Action::BoxPower(false),
+            Action::ResetBox,
+            Action::SleepTimer(30),
+            Action::Macro(vec![MacroStep {
+                action: Action::HomeInput,
+                delay_ms: 500,
+            }]),
+        ] {
+            let compact = action.to_string();
+            let parsed: Action = compact
+                .parse()
+                .unwrap_or_else(|e| panic!("{:?} from {:?}: {}", compact, action, e));
+            assert_eq!(
+                serde_json::to_value(&parsed).unwrap(),
+                serde_json::to_value(&action).unwrap(),
+                "{:?}",
+                compact
+            );
+        }
+        assert_eq!(
+            Action::Script("123".to_string()).to_string(),
+            r#"script:"123""#
+        );
+        assert_eq!(
+            Action::Script("movie".to_string()).to_string(),
+            "script:movie"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        for payload in [
+            "",
+            "not json",
+            r#"{"value": "Netflix"}"#,
+            r#"{"action": "launch_missiles"}"#,
+            r#"{"action": "open_app"}"#,
+            r#"{"action": "sleep_timer", "value": "soon"}"#,
+            r#"{"action": "sony_command", "value": "NotACommand"}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<IncomingCommand>(payload).is_err(),
+                "{:?} parsed",
+                payload
+            );
+        }
+    }
+}
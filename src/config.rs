@@ -0,0 +1,633 @@
+use std::{collections::HashMap, env, fs, str::FromStr, time::Duration};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
+
+use crate::{
+    action::{Action, MacroStep},
+    ha_dialect::HaDialect,
+    remote_feedback::RemoteFeedback,
+    sony_commands::SonyCommand,
+    util::die,
+};
+
+/// What to leave things set to when the mediator is stopped
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownAction {
+    None,
+    PassthruOff,
+    SelectHome,
+}
+
+/// Passthru setting while the TV is unreachable
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnreachablePassthru {
+    Off,
+    On,
+    /// Whatever it would be for the power and input last reported
+    LastKnown,
+}
+
+/// A TV input, by the media title HA reports for it and the remote command that selects it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputChoice {
+    pub title: String,
+    pub command: SonyCommand,
+}
+
+/// How a button behaves when the remote keeps sending its code, e.g. while it's held
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RepeatRule {
+    /// Whether repeats do anything. If not, the code is ignored until it has stopped
+    /// arriving for the interval; if so, repeats are let through at most once per interval.
+    pub repeat: bool,
+    pub interval_ms: u64,
+}
+
+/// Runtime settings, read from environment variables at startup
+#[derive(Serialize, Debug)]
+pub struct Config {
+    /// Password for the broker; not needed when a client certificate is used instead
+    #[serde(serialize_with = "redacted")]
+    pub mqtt_pass: Option<String>,
+    pub mqtt_port: u16,
+    /// CA certificate (PEM) to connect to the broker over TLS with, if any
+    pub mqtt_ca_file: Option<String>,
+    /// Client certificate and key (PEM) for brokers that want mutual TLS
+    pub mqtt_client_cert_file: Option<String>,
+    pub mqtt_client_key_file: Option<String>,
+    /// For an encrypted client key, from `MQTT_CLIENT_KEY_PASSPHRASE` or the file named by
+    /// `MQTT_CLIENT_KEY_PASSPHRASE_FILE`
+    #[serde(serialize_with = "redacted")]
+    pub mqtt_client_key_passphrase: Option<String>,
+    /// Outgoing MQTT requests that can be queued, e.g. while the broker is unreachable; any
+    /// more are dropped. The most publishes one message can make (a volume press with many
+    /// steps) is checked against this at startup.
+    pub mqtt_request_capacity: usize,
+    /// Wait this long after starting before connecting to MQTT, e.g. for networking to come
+    /// up on a cold boot
+    pub startup_delay: Option<Duration>,
+    /// Wait between MQTT reconnect attempts, doubling from the initial delay up to the max
+    pub reconnect_initial_delay: Duration,
+    pub reconnect_max_delay: Duration,
+    /// Fraction (0.0 to 1.0) to randomly vary each reconnect delay by
+    pub reconnect_jitter: f64,
+    /// The MQTT connection thread is restarted if it crashes, up to this many times within
+    /// the window; after that the mediator exits, so whatever supervises it can restart it
+    pub connection_restart_limit: usize,
+    pub connection_restart_window: Duration,
+    pub usb_readiness_topic: String,
+    /// Send the remote's passthru setting at all; if not, it's left however the remote has it
+    pub manage_passthru: bool,
+    /// What to publish to the remote's passthru setting topic, for firmware that wants
+    /// something other than `ON` and `OFF`
+    pub passthru_on_payload: String,
+    pub passthru_off_payload: String,
+    pub unreachable_passthru: UnreachablePassthru,
+    /// How long USB readiness has to hold before it's passed on
+    pub usb_settle_time: Duration,
+    /// More readiness changes than this within the window are reported as the line being unstable
+    pub usb_flap_limit: usize,
+    pub usb_flap_window: Duration,
+    /// How commands are put to Home Assistant, and the topic base for the native dialect
+    pub ha_dialect: HaDialect,
+    pub ha_native_topic_base: String,
+    /// Topic accepting any action as a JSON payload
+    pub command_topic: String,
+    /// Publishing anything to the request topic gets a full status document, including this
+    /// config, published on the status topic
+    pub status_request_topic: String,
+    pub status_topic: String,
+    /// Other names Sony commands can go by in actions on the command topic
+    pub command_aliases: HashMap<String, SonyCommand>,
+    /// Extra MQTT topics to listen on, and what to do when anything is published to them
+    pub command_topics: HashMap<String, Action>,
+    /// Where to publish a record of every command sent to Home Assistant, if anywhere
+    pub actions_topic: Option<String>,
+    /// Allow the raw command action, which can send the TV anything
+    pub allow_raw_commands: bool,
+    /// Remote buttons to handle with a specific action instead of their built-in behavior
+    pub consumer_code_actions: HashMap<u8, Action>,
+    pub key_code_actions: HashMap<u8, Action>,
+    /// Where to publish the list of codes the remote sent that nothing handles, if anywhere
+    pub unmapped_codes_topic: Option<String>,
+    /// Repeat behavior for consumer and key codes; codes not listed pass every repeat through
+    pub consumer_code_repeat: HashMap<u8, RepeatRule>,
+    pub key_code_repeat: HashMap<u8, RepeatRule>,
+    /// Remote buttons to ignore entirely, whatever they're mapped to
+    pub disabled_consumer_codes: Vec<u8>,
+    pub disabled_key_codes: Vec<u8>,
+    pub disable_power_button: bool,
+    /// Consumer codes that mean the user wants off the home input, whatever action they're
+    /// mapped to
+    pub input_change_codes: Vec<u8>,
+    /// What the home button does while the TV is on the home input, instead of opening the
+    /// TV's launcher
+    pub home_button_on_home_input: Option<Action>,
+    /// Steps of the recover action. Defaults to turning guest mode off, turning the TV on,
+    /// selecting the home input, waking the box, and resyncing.
+    pub recover_macro: Vec<MacroStep>,
+    /// Steps of the all off action. Defaults to turning the TV off and then sleeping the box;
+    /// add a script step to also turn off a receiver. The passthru setting and LED follow the
+    /// TV turning off by themselves.
+    pub all_off_macro: Vec<MacroStep>,
+    /// Inputs to step through with the cycle input action
+    pub cycle_inputs: Vec<InputChoice>,
+    /// Home Assistant bridge topic for launching apps on the box on the home input
+    pub android_app_topic: String,
+    /// Home Assistant scripts that wake, sleep, and reset the box on the home input
+    pub box_wake_script: String,
+    pub box_sleep_script: String,
+    pub box_reset_script: String,
+    /// Box wakes within this long of the last wake are ignored, and likewise resets
+    pub box_wake_dedup_window: Duration,
+    /// Where to confirm that a command topic message was acted on, if anywhere
+    pub command_ack_topic: Option<String>,
+    /// HA statestream topic for the TV's foreground app, e.g.
+    /// `homeassistant_statestream/media_player/sony_bravia/app_name`; app tracking is off
+    /// unless this is set
+    pub tv_app_topic: Option<String>,
+    /// TV inputs (by media title) where the managed box is plugged in
+    pub home_inputs: Vec<String>,
+    /// Remote command that selects the home input
+    pub home_input_command: SonyCommand,
+    /// What to call the TV being on the home input in published state
+    pub managed_state_label: String,
+    /// TV state is published at most this often; brief flickers in between are never published
+    pub state_publish_interval: Duration,
+    /// Switch back to the home input if the TV wanders off it on its own
+    pub keep_home_input: bool,
+    /// How long to give the TV to act on an input switch before deciding it ignored it
+    pub command_verify_delay: Duration,
+    /// Resend an ignored input switch once
+    pub retry_ignored_commands: bool,
+    /// After the TV turns on, hold off on automatic input correction for this long while its
+    /// reported state settles down
+    pub settling_window: Duration,
+    /// Turn guest mode back off automatically after this long
+    pub guest_mode_timeout: Option<Duration>,
+    /// TV inputs (by media title) whose audio goes through the AVR rather than the TV speakers
+    pub avr_inputs: Vec<String>,
+    pub avr_volume_up_script: String,
+    pub avr_volume_down_script: String,
+    /// Volume steps per remote press, by TV input (media title), and for AVR inputs not
+    /// listed there; any other input steps once
+    pub volume_steps: HashMap<String, u32>,
+    pub avr_volume_steps: u32,
+    /// Highest TV volume level (0.0 to 1.0) the remote is allowed to reach
+    pub max_volume: Option<f64>,
+    /// Power presses closer together than this are treated as button bounce
+    pub power_debounce: Duration,
+    /// What a quick double press of power does instead of toggling the TV, if anything. With
+    /// this set, a single press only toggles power once the double press window has passed.
+    pub power_double_press_action: Option<Action>,
+    pub power_double_press_window: Duration,
+    /// How often to automatically resync, if at all
+    pub resync_interval: Option<Duration>,
+    /// How often to re-send the passthru flag on its own, if at all, in case one was lost
+    pub passthru_reassert_interval: Option<Duration>,
+    /// Tell short and long presses of OK apart. The remote firmware must send an `o` event when
+    /// OK is released, since OK is only acted on at release when this is enabled.
+    pub ok_long_press: bool,
+    pub ok_long_press_threshold: Duration,
+    pub ok_long_press_command: SonyCommand,
+    /// By default passthru is turned off, so the remote isn't left driving the box through a
+    /// mediator that's gone
+    pub shutdown_action: ShutdownAction,
+    /// Remote LED colors for TV on the home input, TV on another input, TV off, and TV
+    /// unreachable
+    pub led_home_input: RemoteFeedback,
+    pub led_other_input: RemoteFeedback,
+    pub led_tv_off: RemoteFeedback,
+    pub led_tv_unreachable: RemoteFeedback,
+    /// Color to briefly flash the remote LED when a button press is acted on, if any
+    pub dispatch_led: Option<RemoteFeedback>,
+    pub dispatch_led_time: Duration,
+    /// Which kinds of button flash the LED: consumer, key, digit, ok, power
+    pub dispatch_led_buttons: Vec<String>,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        Config::from_vars(&|name| env::var(name).ok())
+    }
+
+    /// Defaults for everything, as if no env vars were set
+    #[cfg(test)]
+    pub fn defaults() -> Config {
+        Config::from_vars(&|_| None)
+    }
+
+    /// Settings from whatever `var` finds for each env var name
+    fn from_vars(var: Lookup) -> Config {
+        Config {
+            mqtt_pass: var("MQTT_PASS"),
+            mqtt_port: env_number(var, "MQTT_PORT", 1883),
+            mqtt_ca_file: var("MQTT_CA_FILE"),
+            mqtt_client_cert_file: var("MQTT_CLIENT_CERT_FILE"),
+            mqtt_client_key_file: var("MQTT_CLIENT_KEY_FILE"),
+            mqtt_client_key_passphrase: var("MQTT_CLIENT_KEY_PASSPHRASE").or_else(|| {
+                var("MQTT_CLIENT_KEY_PASSPHRASE_FILE").map(|path| {
+                    fs::read_to_string(&path)
+                        .unwrap_or_else(|e| die(&format!("Can't read {}: {}", path, e)))
+                        .trim_end()
+                        .to_string()
+                })
+            }),
+            mqtt_request_capacity: env_number(var, "MQTT_REQUEST_CAPACITY", 32),
+            startup_delay: env_opt_millis(var, "STARTUP_DELAY_MS"),
+            reconnect_initial_delay: env_millis(var, "RECONNECT_INITIAL_DELAY_MS", 1000),
+            reconnect_max_delay: env_millis(var, "RECONNECT_MAX_DELAY_MS", 60000),
+            reconnect_jitter: env_number(var, "RECONNECT_JITTER", 0.1),
+            connection_restart_limit: env_number(var, "CONNECTION_RESTART_LIMIT", 3),
+            connection_restart_window: env_millis(var, "CONNECTION_RESTART_WINDOW_MS", 60_000),
+            usb_readiness_topic: env_or(var, "USB_READINESS_TOPIC", "air-remote/usb-readiness"),
+            manage_passthru: env_bool(var, "MANAGE_PASSTHRU", true),
+            passthru_on_payload: env_or(var, "PASSTHRU_ON_PAYLOAD", "ON"),
+            passthru_off_payload: env_or(var, "PASSTHRU_OFF_PAYLOAD", "OFF"),
+            unreachable_passthru: env_named(var, "UNREACHABLE_PASSTHRU", UnreachablePassthru::Off),
+            usb_settle_time: env_millis(var, "USB_SETTLE_MS", 2000),
+            usb_flap_limit: env_number(var, "USB_FLAP_LIMIT", 5),
+            usb_flap_window: env_millis(var, "USB_FLAP_WINDOW_MS", 30_000),
+            ha_dialect: env_named(var, "HA_DIALECT", HaDialect::Bridge),
+            ha_native_topic_base: env_or(var, "HA_NATIVE_TOPIC_BASE", "air-remote-mediator/ha"),
+            command_topic: env_or(var, "COMMAND_TOPIC", "air-remote-mediator/command"),
+            status_request_topic: env_or(
+                var,
+                "STATUS_REQUEST_TOPIC",
+                "air-remote-mediator/status/get",
+            ),
+            status_topic: env_or(var, "STATUS_TOPIC", "air-remote-mediator/status"),
+            command_aliases: env_json(var, "COMMAND_ALIASES"),
+            command_topics: env_json(var, "COMMAND_TOPICS"),
+            actions_topic: var("ACTIONS_TOPIC"),
+            allow_raw_commands: env_bool(var, "ALLOW_RAW_COMMANDS", false),
+            consumer_code_actions: env_code_map(var, "CONSUMER_CODE_ACTIONS"),
+            key_code_actions: env_code_map(var, "KEY_CODE_ACTIONS"),
+            consumer_code_repeat: env_code_map(var, "CONSUMER_CODE_REPEAT"),
+            key_code_repeat: env_code_map(var, "KEY_CODE_REPEAT"),
+            unmapped_codes_topic: var("UNMAPPED_CODES_TOPIC"),
+            disabled_consumer_codes: env_code_list(var, "DISABLED_CONSUMER_CODES", &[]),
+            disabled_key_codes: env_code_list(var, "DISABLED_KEY_CODES", &[]),
+            disable_power_button: env_bool(var, "DISABLE_POWER_BUTTON", false),
+            input_change_codes: env_code_list(var, "INPUT_CHANGE_CODES", &[0x86, 0x9A, 0x9C, 0x9D]),
+            home_button_on_home_input: env_opt_action(var, "HOME_BUTTON_HOME_INPUT_ACTION"),
+            recover_macro: env_json_or(var, "RECOVER_MACRO", default_recover_macro()),
+            all_off_macro: env_json_or(var, "ALL_OFF_MACRO", default_all_off_macro()),
+            cycle_inputs: env_json(var, "CYCLE_INPUTS"),
+            android_app_topic: env_or(
+                var,
+                "ANDROID_APP_TOPIC",
+                "homeassistant_cmd/media_player_app/dennis",
+            ),
+            box_wake_script: env_or(var, "BOX_WAKE_SCRIPT", "wake_dennis"),
+            box_sleep_script: env_or(var, "BOX_SLEEP_SCRIPT", "sleep_dennis"),
+            box_reset_script: env_or(var, "BOX_RESET_SCRIPT", "reset_dennis"),
+            box_wake_dedup_window: env_millis(var, "BOX_WAKE_DEDUP_MS", 10000),
+            command_ack_topic: var("COMMAND_ACK_TOPIC"),
+            tv_app_topic: var("TV_APP_TOPIC"),
+            home_inputs: env_list(var, "HOME_INPUTS", &["HDMI 1"]),
+            home_input_command: env_named(var, "HOME_INPUT_COMMAND", SonyCommand::Hdmi1),
+            managed_state_label: env_or(var, "MANAGED_STATE_LABEL", "on_managed"),
+            state_publish_interval: env_millis(var, "STATE_PUBLISH_INTERVAL_MS", 1000),
+            keep_home_input: env_bool(var, "KEEP_HOME_INPUT", false),
+            command_verify_delay: env_millis(var, "COMMAND_VERIFY_DELAY_MS", 10000),
+            retry_ignored_commands: env_bool(var, "RETRY_IGNORED_COMMANDS", false),
+            settling_window: env_millis(var, "SETTLING_WINDOW_MS", 10000),
+            guest_mode_timeout: env_opt_millis(var, "GUEST_MODE_TIMEOUT_MS"),
+            avr_inputs: env_list(var, "AVR_INPUTS", &[]),
+            avr_volume_up_script: env_or(var, "AVR_VOLUME_UP_SCRIPT", "avr_volume_up"),
+            avr_volume_down_script: env_or(var, "AVR_VOLUME_DOWN_SCRIPT", "avr_volume_down"),
+            volume_steps: env_json(var, "VOLUME_STEPS"),
+            avr_volume_steps: env_number(var, "AVR_VOLUME_STEPS", 1),
+            max_volume: env_opt_number(var, "MAX_VOLUME"),
+            power_debounce: env_millis(var, "POWER_DEBOUNCE_MS", 1000),
+            power_double_press_action: env_opt_action(var, "POWER_DOUBLE_PRESS_ACTION"),
+            power_double_press_window: env_millis(var, "POWER_DOUBLE_PRESS_MS", 500),
+            resync_interval: env_opt_millis(var, "RESYNC_INTERVAL_MS"),
+            passthru_reassert_interval: env_opt_millis(var, "PASSTHRU_REASSERT_INTERVAL_MS"),
+            ok_long_press: env_bool(var, "OK_LONG_PRESS", false),
+            ok_long_press_threshold: env_millis(var, "OK_LONG_PRESS_THRESHOLD_MS", 600),
+            ok_long_press_command: env_named(var, "OK_LONG_PRESS_COMMAND", SonyCommand::ActionMenu),
+            shutdown_action: env_named(var, "SHUTDOWN_ACTION", ShutdownAction::PassthruOff),
+            led_home_input: env_named(var, "LED_HOME_INPUT", RemoteFeedback::Green),
+            led_other_input: env_named(var, "LED_OTHER_INPUT", RemoteFeedback::Red),
+            led_tv_off: env_named(var, "LED_TV_OFF", RemoteFeedback::Off),
+            led_tv_unreachable: env_named(var, "LED_TV_UNREACHABLE", RemoteFeedback::Off),
+            dispatch_led: env_opt_named(var, "DISPATCH_LED"),
+            dispatch_led_time: env_millis(var, "DISPATCH_LED_MS", 200),
+            dispatch_led_buttons: env_list(
+                var,
+                "DISPATCH_LED_BUTTONS",
+                &["consumer", "key", "digit", "ok", "power"],
+            ),
+        }
+    }
+
+    /// Check for settings that parse fine but can't work together
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+        if self.mqtt_pass.is_none() && self.mqtt_client_cert_file.is_none() {
+            errors
+                .push("Need MQTT_PASS, or MQTT_CLIENT_CERT_FILE to use a certificate".to_string());
+        }
+        if self.mqtt_client_cert_file.is_some() && self.mqtt_ca_file.is_none() {
+            errors.push("MQTT_CLIENT_CERT_FILE needs MQTT_CA_FILE to connect over TLS".to_string());
+        }
+        if self.mqtt_client_cert_file.is_some() != self.mqtt_client_key_file.is_some() {
+            errors.push(
+                "MQTT_CLIENT_CERT_FILE and MQTT_CLIENT_KEY_FILE must be set together".to_string(),
+            );
+        }
+        if self.mqtt_request_capacity == 0 {
+            errors.push("MQTT_REQUEST_CAPACITY must be at least 1".to_string());
+        }
+        // Each volume step is its own publish, and another when actions are reported
+        let publishes_per_step = if self.actions_topic.is_some() { 2 } else { 1 };
+        let most_volume_steps = self
+            .volume_steps
+            .values()
+            .copied()
+            .chain([self.avr_volume_steps])
+            .max()
+            .unwrap_or_default() as usize;
+        if most_volume_steps * publishes_per_step > self.mqtt_request_capacity {
+            errors.push(format!(
+                "VOLUME_STEPS and AVR_VOLUME_STEPS can't be more than {} with MQTT_REQUEST_CAPACITY {}",
+                self.mqtt_request_capacity / publishes_per_step,
+                self.mqtt_request_capacity,
+            ));
+        }
+        if self.reconnect_initial_delay > self.reconnect_max_delay {
+            errors.push(
+                "RECONNECT_INITIAL_DELAY_MS must not be more than RECONNECT_MAX_DELAY_MS"
+                    .to_string(),
+            );
+        }
+        // Also rules out NaN, which would make every delay NaN
+        if !(0.0..=1.0).contains(&self.reconnect_jitter) {
+            errors.push("RECONNECT_JITTER must be between 0.0 and 1.0".to_string());
+        }
+        if self
+            .max_volume
+            .is_some_and(|max| !(0.0..=1.0).contains(&max))
+        {
+            errors.push("MAX_VOLUME must be between 0.0 and 1.0".to_string());
+        }
+        if self.passthru_on_payload == self.passthru_off_payload {
+            errors.push("PASSTHRU_ON_PAYLOAD and PASSTHRU_OFF_PAYLOAD must differ".to_string());
+        }
+        if self.home_inputs.is_empty() {
+            errors.push("HOME_INPUTS must name at least one input".to_string());
+        }
+        if self
+            .resync_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            errors.push("RESYNC_INTERVAL_MS must be more than 0".to_string());
+        }
+        if self
+            .passthru_reassert_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            errors.push("PASSTHRU_REASSERT_INTERVAL_MS must be more than 0".to_string());
+        }
+        for topic in self.command_topics.keys() {
+            if *topic == self.command_topic {
+                errors.push(format!(
+                    "COMMAND_TOPICS repeats the command topic {}",
+                    topic
+                ));
+            }
+        }
+        for alias in self.command_aliases.keys() {
+            let shadows_command =
+                serde_json::from_value::<SonyCommand>(serde_json::Value::String(alias.clone()))
+                    .is_ok();
+            if shadows_command {
+                errors.push(format!(
+                    "COMMAND_ALIASES alias {} is already a command",
+                    alias
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn default_recover_macro() -> Vec<MacroStep> {
+    let step = |action, delay_ms| MacroStep { action, delay_ms };
+    vec![
+        step(Action::GuestMode(false), 0),
+        step(Action::Power(true), 0),
+        step(Action::HomeInput, 5000),
+        step(Action::BoxPower(true), 2000),
+        step(Action::Resync, 2000),
+    ]
+}
+
+fn default_all_off_macro() -> Vec<MacroStep> {
+    let step = |action, delay_ms| MacroStep { action, delay_ms };
+    vec![
+        step(Action::Power(false), 0),
+        step(Action::BoxPower(false), 2000),
+    ]
+}
+
+fn redacted<T, S: Serializer>(_: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+/// Finds the value of an env var, if it's set
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+fn env_or(var: Lookup, name: &str, default: &str) -> String {
+    var(name).unwrap_or_else(|| default.to_string())
+}
+
+/// A unit enum variant, by its serialized name
+fn env_named<T: DeserializeOwned>(var: Lookup, name: &str, default: T) -> T {
+    match var(name) {
+        Some(value) => serde_json::from_value(serde_json::Value::String(value.clone()))
+            .unwrap_or_else(|_| {
+                die(&format!(
+                    "Env var {} has unrecognized value {:?}",
+                    name, value
+                ))
+            }),
+        None => default,
+    }
+}
+
+/// An action as JSON, or in compact form like `toggle_guest_mode`
+fn env_opt_action(var: Lookup, name: &str) -> Option<Action> {
+    var(name).map(|value| {
+        let parsed = if value.trim_start().starts_with('{') {
+            serde_json::from_str(&value)
+        } else {
+            value.trim().parse()
+        };
+        parsed.unwrap_or_else(|e| die(&format!("Env var {} is not valid: {}", name, e)))
+    })
+}
+
+fn env_opt_named<T: DeserializeOwned>(var: Lookup, name: &str) -> Option<T> {
+    var(name).map(|value| {
+        serde_json::from_value(serde_json::Value::String(value.clone())).unwrap_or_else(|_| {
+            die(&format!(
+                "Env var {} has unrecognized value {:?}",
+                name, value
+            ))
+        })
+    })
+}
+
+fn env_json<T: DeserializeOwned + Default>(var: Lookup, name: &str) -> T {
+    env_json_or(var, name, T::default())
+}
+
+fn env_json_or<T: DeserializeOwned>(var: Lookup, name: &str, default: T) -> T {
+    match var(name) {
+        Some(value) => serde_json::from_str(&value)
+            .unwrap_or_else(|e| die(&format!("Env var {} is not valid: {}", name, e))),
+        None => default,
+    }
+}
+
+/// JSON object from hex codes like `"0x9A"` to actions or other settings
+fn env_code_map<T: DeserializeOwned>(var: Lookup, name: &str) -> HashMap<u8, T> {
+    let by_name: HashMap<String, T> = env_json(var, name);
+    by_name
+        .into_iter()
+        .map(|(code, value)| (parse_code(name, &code), value))
+        .collect()
+}
+
+/// Comma-separated hex codes like `0x86`
+fn env_code_list(var: Lookup, name: &str, default: &[u8]) -> Vec<u8> {
+    match var(name) {
+        Some(_) => env_list(var, name, &[])
+            .iter()
+            .map(|code| parse_code(name, code))
+            .collect(),
+        None => default.to_vec(),
+    }
+}
+
+fn parse_code(name: &str, code: &str) -> u8 {
+    u8::from_str_radix(code.trim_start_matches("0x"), 16)
+        .unwrap_or_else(|_| die(&format!("Env var {} has invalid code {:?}", name, code)))
+}
+
+fn env_bool(var: Lookup, name: &str, default: bool) -> bool {
+    match var(name) {
+        Some(value) => matches!(value.as_str(), "1" | "true" | "yes" | "on"),
+        None => default,
+    }
+}
+
+fn env_number<T: FromStr>(var: Lookup, name: &str, default: T) -> T {
+    match var(name) {
+        Some(value) => value
+            .parse()
+            .unwrap_or_else(|_| die(&format!("Env var {} must be a number", name))),
+        None => default,
+    }
+}
+
+fn env_millis(var: Lookup, name: &str, default: u64) -> Duration {
+    Duration::from_millis(env_number(var, name, default))
+}
+
+fn env_opt_number<T: FromStr>(var: Lookup, name: &str) -> Option<T> {
+    var(name).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| die(&format!("Env var {} must be a number", name)))
+    })
+}
+
+fn env_opt_millis(var: Lookup, name: &str) -> Option<Duration> {
+    env_opt_number(var, name).map(Duration::from_millis)
+}
+
+/// Comma-separated list
+fn env_list(var: Lookup, name: &str, default: &[&str]) -> Vec<String> {
+    match var(name) {
+        Some(value) => value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        None => default.iter().map(|item| item.to_string()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Defaults plus the one setting that's required
+    fn valid_config() -> Config {
+        let mut config = Config::defaults();
+        config.mqtt_pass = Some("secret".to_string());
+        config
+    }
+
+    #[test]
+    fn passthru_payloads_must_differ() {
+        let mut config = valid_config();
+        config.passthru_on_payload = "P".to_string();
+        config.passthru_off_payload = "p".to_string();
+        assert!(config.validate().is_ok());
+
+        config.passthru_off_payload = "P".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn aliases_must_not_shadow_commands() {
+        let mut config = valid_config();
+        config.command_aliases = HashMap::from([("telly".to_string(), SonyCommand::Home)]);
+        assert!(config.validate().is_ok());
+
+        config
+            .command_aliases
+            .insert("Netflix".to_string(), SonyCommand::Home);
+        let errors = config.validate().unwrap_err();
+        assert_eq!(
+            errors,
+            ["COMMAND_ALIASES alias Netflix is already a command"]
+        );
+    }
+
+    #[test]
+    fn reconnect_jitter_must_be_a_fraction() {
+        let mut config = valid_config();
+        for jitter in [0.0, 0.2, 1.0] {
+            config.reconnect_jitter = jitter;
+            assert!(config.validate().is_ok(), "{} rejected", jitter);
+        }
+        for jitter in [-0.1, 5.0, f64::NAN, f64::INFINITY] {
+            config.reconnect_jitter = jitter;
+            assert!(config.validate().is_err(), "{} accepted", jitter);
+        }
+    }
+
+    #[test]
+    fn volume_steps_must_fit_request_queue() {
+        let mut config = valid_config();
+        config.mqtt_request_capacity = 32;
+        config.volume_steps = HashMap::from([("HDMI 2".to_string(), 20)]);
+        assert!(config.validate().is_ok());
+
+        config.actions_topic = Some("air-remote-mediator/actions".to_string());
+        let errors = config.validate().unwrap_err();
+        assert!(errors[0].starts_with("VOLUME_STEPS"));
+    }
+}
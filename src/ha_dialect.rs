@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const BRIDGE_SCRIPT_TOPIC_BASE: &str = "homeassistant_cmd/script/";
+const BRIDGE_REMOTE_COMMAND_TOPIC: &str = "homeassistant_cmd/remote_command/sony_bravia";
+const BRIDGE_MEDIA_PLAYER_APP_TOPIC: &str = "homeassistant_cmd/media_player_app/sony_bravia";
+
+/// How commands are put to Home Assistant over MQTT
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HaDialect {
+    /// A bridge that maps `homeassistant_cmd/...` topics straight onto HA services, with
+    /// the service target in the topic and a bare payload
+    Bridge,
+    /// Plain JSON messages under a topic base of our own, for HA automations using the
+    /// standard MQTT integration to act on
+    Native,
+}
+
+/// Where to publish a command, and what
+pub struct HaMessage {
+    pub topic: String,
+    pub payload: String,
+}
+
+impl HaDialect {
+    pub fn script(self, native_base: &str, name: &str) -> HaMessage {
+        match self {
+            HaDialect::Bridge => HaMessage {
+                topic: format!("{}{}", BRIDGE_SCRIPT_TOPIC_BASE, name),
+                payload: String::new(),
+            },
+            HaDialect::Native => native(native_base, "script", json!({ "script": name })),
+        }
+    }
+
+    /// A Sony remote command, by its name in the TV's IRCC command list
+    pub fn remote_command(self, native_base: &str, command: &str) -> HaMessage {
+        match self {
+            HaDialect::Bridge => HaMessage {
+                topic: BRIDGE_REMOTE_COMMAND_TOPIC.to_string(),
+                payload: command.to_string(),
+            },
+            HaDialect::Native => {
+                native(native_base, "remote_command", json!({ "command": command }))
+            }
+        }
+    }
+
+    pub fn tv_app(self, native_base: &str, app_name: &str) -> HaMessage {
+        match self {
+            HaDialect::Bridge => HaMessage {
+                topic: BRIDGE_MEDIA_PLAYER_APP_TOPIC.to_string(),
+                payload: app_name.to_string(),
+            },
+            HaDialect::Native => native(native_base, "tv_app", json!({ "app": app_name })),
+        }
+    }
+
+    /// An app on the box on the home input; the bridge gets its own topic for that box
+    pub fn android_app(self, native_base: &str, bridge_topic: &str, package: &str) -> HaMessage {
+        match self {
+            HaDialect::Bridge => HaMessage {
+                topic: bridge_topic.to_string(),
+                payload: package.to_string(),
+            },
+            HaDialect::Native => native(native_base, "android_app", json!({ "package": package })),
+        }
+    }
+
+    /// Topic prefixes that everything above is published under
+    pub fn command_topics(self, native_base: &str, bridge_android_topic: &str) -> Vec<String> {
+        match self {
+            HaDialect::Bridge => vec![
+                BRIDGE_REMOTE_COMMAND_TOPIC.to_string(),
+                BRIDGE_MEDIA_PLAYER_APP_TOPIC.to_string(),
+                BRIDGE_SCRIPT_TOPIC_BASE.to_string(),
+                bridge_android_topic.to_string(),
+            ],
+            HaDialect::Native => vec![format!("{}/", native_base)],
+        }
+    }
+}
+
+fn native(base: &str, kind: &str, payload: serde_json::Value) -> HaMessage {
+    HaMessage {
+        topic: format!("{}/{}", base, kind),
+        payload: payload.to_string(),
+    }
+}
@@ -5,38 +5,104 @@
  * ~3-8 seconds: homeassistant_statestream/media_player/sony_bravia/media_title: "Smart TV"
  */
 
+mod action;
+mod config;
+mod ha_dialect;
+mod mqtt_sink;
+mod mqtt_tls;
+mod power;
+mod recording;
+mod remote_feedback;
 mod sony_commands;
+mod tv_state;
+mod usb_readiness;
+mod util;
 
-use std::{env, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    env, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use rumqttc::{Client, Event::Incoming, MqttOptions, Packet::Publish, QoS};
-use serde::Deserialize;
+use action::{Action, CommandRequest, IncomingCommand, MacroStep};
+use config::{Config, ShutdownAction, UnreachablePassthru};
+use ha_dialect::HaMessage;
+use mqtt_sink::{ActionReporter, DryRunSink, MqttSink, MqttSource, Notification};
+use power::{PowerMachine, PowerState};
+use recording::{Recorder, RecordingSource};
+use rumqttc::{
+    Client, Connection, ConnectionError, Event,
+    Event::Incoming,
+    MqttOptions, Outgoing,
+    Packet::{ConnAck, Publish},
+    QoS,
+};
+use serde::{Deserialize, Serialize};
 use serde_hex::{SerHex, StrictCapPfx};
+use serde_json::json;
 use serde_variant::to_variant_name;
+use signal_hook::consts::{SIGINT, SIGTERM};
 use sony_commands::SonyCommand;
+use tv_state::{StateHistory, TvState};
+use usb_readiness::{UsbReadiness, UsbReadinessEvent};
+use util::{die, Backoff, Clock, RestartBudget, SystemClock};
+
+const MQTT_BROKER_HOST: &str = "mqtt.sinclair.pipsimon.com";
 
 const AIR_REMOTE_TOPIC: &str = "air-remote/events";
 const TV_STATE_TOPIC: &str = "homeassistant_statestream/media_player/sony_bravia/state";
 const TV_INPUT_TOPIC: &str = "homeassistant_statestream/media_player/sony_bravia/media_title";
+const TV_VOLUME_TOPIC: &str = "homeassistant_statestream/media_player/sony_bravia/volume_level";
+const TV_MUTED_TOPIC: &str = "homeassistant_statestream/media_player/sony_bravia/is_volume_muted";
+const TV_STATUS_TOPICS: [&str; 4] = [
+    TV_STATE_TOPIC,
+    TV_INPUT_TOPIC,
+    TV_VOLUME_TOPIC,
+    TV_MUTED_TOPIC,
+];
 
 const AIR_REMOTE_PASSTHRU_TOPIC: &str = "air-remote/passthru-setting";
-const TV_REMOTE_COMMAND_TOPIC: &str = "homeassistant_cmd/remote_command/sony_bravia";
-const TV_MEDIA_PLAYER_APP_TOPIC: &str = "homeassistant_cmd/media_player_app/sony_bravia";
+const AIR_REMOTE_LED_TOPIC: &str = "air-remote/led";
+
+const TELEMETRY_TOPIC: &str = "air-remote-mediator/telemetry";
+const GUEST_MODE_TOPIC: &str = "air-remote-mediator/guest-mode";
+const MEDIATOR_TV_STATE_TOPIC: &str = "air-remote-mediator/tv-state";
+const SLEEP_TIMER_TOPIC: &str = "air-remote-mediator/sleep-timer";
+const VOLUME_CAPPED_TOPIC: &str = "air-remote-mediator/volume-capped";
+const BATTERY_TOPIC: &str = "air-remote/battery";
+const BATTERY_DISCOVERY_TOPIC: &str = "homeassistant/sensor/air_remote_battery/config";
 
-const HA_SCRIPT_TOPIC_BASE: &str = "homeassistant_cmd/script/";
 const HA_SCRIPT_TOGGLE_TV_AND_DENNIS: &str = "toggle_tv_and_dennis";
 const HA_SCRIPT_TV_VOLUME_UP: &str = "tv_volume_up";
 const HA_SCRIPT_TV_VOLUME_DOWN: &str = "tv_volume_down";
 const HA_SCRIPT_NOTICE_DENNIS_USB_OFF: &str = "notice_dennis_usb_readiness_off";
 const HA_SCRIPT_NOTICE_DENNIS_USB_ON: &str = "notice_dennis_usb_readiness_on";
+const HA_SCRIPT_NOTICE_DENNIS_USB_UNSTABLE: &str = "notice_dennis_usb_readiness_unstable";
 
 const CONSUMER_CODE_VOLUME_UP: u8 = 0xE9;
 const CONSUMER_CODE_VOLUME_DOWN: u8 = 0xEA;
 const CONSUMER_CODE_MENU_ESCAPE: u8 = 0x46;
+const CONSUMER_CODE_CLOSED_CAPTION: u8 = 0x61;
 const CONSUMER_CODE_CHANNEL: u8 = 0x86;
+const CONSUMER_CODE_CHANNEL_INCREMENT: u8 = 0x9C;
+const CONSUMER_CODE_CHANNEL_DECREMENT: u8 = 0x9D;
 const CONSUMER_CODE_MEDIA_SELECT_HOME: u8 = 0x9A;
 const CONSUMER_CODE_PLAY_PAUSE: u8 = 0xCD;
 
+const HID_KEY_ENTER: u8 = 0x28;
+const HID_KEY_ESCAPE: u8 = 0x29;
+const HID_KEY_BACKSPACE: u8 = 0x2A;
+const HID_KEY_HOME: u8 = 0x4A;
+const HID_KEY_PAGE_UP: u8 = 0x4B;
+const HID_KEY_END: u8 = 0x4D;
+const HID_KEY_PAGE_DOWN: u8 = 0x4E;
 const HID_KEY_ARROW_RIGHT: u8 = 0x4F;
 const HID_KEY_ARROW_LEFT: u8 = 0x50;
 const HID_KEY_ARROW_DOWN: u8 = 0x51;
@@ -47,7 +113,11 @@ const HID_KEY_ARROW_UP: u8 = 0x52;
 enum InputEvent {
     #[serde(rename = "A")]
     AsciiKey {
-        #[allow(dead_code)]
+        #[serde(with = "SerHex::<StrictCapPfx>")]
+        data: u8,
+    },
+    #[serde(rename = "B")]
+    BatteryLevel {
         #[serde(with = "SerHex::<StrictCapPfx>")]
         data: u8,
     },
@@ -65,6 +135,9 @@ enum InputEvent {
     NetworkConnected,
     #[serde(rename = "O")]
     OkButton,
+    /// Only sent by remote firmware that reports button releases
+    #[serde(rename = "o")]
+    OkButtonRelease,
     #[serde(rename = "W")]
     PowerButton,
     #[serde(rename = "U")]
@@ -74,148 +147,2422 @@ enum InputEvent {
     },
 }
 
+impl InputEvent {
+    /// Which kind of button was pressed, if this is a button press at all
+    fn button_kind(&self) -> Option<&'static str> {
+        match self {
+            InputEvent::ConsumerCode { .. } => Some("consumer"),
+            InputEvent::KeyCode { .. } => Some("key"),
+            InputEvent::AsciiKey { .. } => Some("digit"),
+            InputEvent::OkButton => Some("ok"),
+            InputEvent::PowerButton => Some("power"),
+            InputEvent::BatteryLevel { .. }
+            | InputEvent::NetworkConnected
+            | InputEvent::OkButtonRelease
+            | InputEvent::UsbReadinessStateChange { .. } => None,
+        }
+    }
+}
+
+const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("GIT_HASH"), ")");
+
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(60);
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[derive(Debug)]
 struct State {
-    tv_is_on: bool,
-    dennis_is_current_input: bool,
+    power: PowerMachine,
+    /// Set while HA reports the TV as unavailable; power is left as last reported meanwhile
+    tv_unreachable: bool,
+    on_home_input: bool,
+    current_input: Option<String>,
+    /// App in the foreground on the TV, if `TV_APP_TOPIC` is set and HA has reported one
+    current_app: Option<String>,
+    /// Volume level as reported by HA, from 0.0 to 1.0
+    volume: Option<f64>,
+    muted: Option<bool>,
+    ok_pressed_at: Option<Instant>,
+    /// When power was pressed, while waiting to see if a second press makes it a double
+    power_pressed_at: Option<Instant>,
+    /// Set when the user asks for another input or app, so leaving the home input isn't undone
+    input_change_requested: bool,
+    /// Set after switching to the home input, to check later that the TV actually did it
+    home_input_check: Option<InputCheck>,
+    /// Index into `CYCLE_INPUTS` of the input last cycled to or reported
+    cycle_position: Option<usize>,
+    /// Automatic input correction is held off until then, while the TV is booting
+    settling_until: Option<Instant>,
+    /// When guest mode was turned on, if it's on
+    guest_mode_since: Option<Instant>,
+    /// When the sleep timer will turn the TV off, if it's running
+    sleep_at: Option<Instant>,
+    /// Minutes remaining as last published, to only publish when it changes
+    sleep_minutes_sent: Option<u64>,
+    usb_readiness: UsbReadiness,
+    /// When to put the LED back after flashing it for a button press
+    led_restore_at: Option<Instant>,
+    /// TV state as last published, and when
+    tv_state_sent: Option<TvState>,
+    tv_state_sent_at: Option<Instant>,
+    /// Remaining steps of the running macro, with when each is due
+    macro_steps: VecDeque<(Instant, Action)>,
+    /// Codes that hit no mapping or built-in behavior, like `consumer 0x9F`
+    unmapped_codes: BTreeSet<String>,
+    /// When the box was last woken
+    box_woken_at: Option<Instant>,
+    /// When the box was last reset
+    box_reset_at: Option<Instant>,
+    /// For buttons with a repeat rule, by event type and code: when each was last acted on,
+    /// or for non-repeating buttons, last received
+    code_last_seen_at: HashMap<(char, u8), Instant>,
 }
 
-fn send_passthru_flag_update(client: &mut Client, state: &State) {
-    client
-        .publish(
-            AIR_REMOTE_PASSTHRU_TOPIC,
-            QoS::AtLeastOnce,
-            false,
-            if state.tv_is_on && state.dennis_is_current_input {
-                "ON"
-            } else {
-                "OFF"
-            },
-        )
-        .unwrap();
+#[derive(Debug)]
+struct InputCheck {
+    due_at: Instant,
+    retried: bool,
 }
 
-fn send_ha_script_command(client: &mut Client, script_name: &str) {
-    client
-        .publish(
-            format!("{}{}", HA_SCRIPT_TOPIC_BASE, script_name),
-            QoS::AtLeastOnce,
-            false,
-            "",
-        )
-        .unwrap();
+impl State {
+    fn new(config: &Config) -> State {
+        State {
+            power: PowerMachine::new(config.power_debounce),
+            tv_unreachable: false,
+            on_home_input: false,
+            current_input: None,
+            current_app: None,
+            volume: None,
+            muted: None,
+            ok_pressed_at: None,
+            power_pressed_at: None,
+            input_change_requested: false,
+            home_input_check: None,
+            cycle_position: None,
+            settling_until: None,
+            guest_mode_since: None,
+            sleep_at: None,
+            sleep_minutes_sent: None,
+            usb_readiness: UsbReadiness::new(
+                config.usb_settle_time,
+                config.usb_flap_limit,
+                config.usb_flap_window,
+            ),
+            led_restore_at: None,
+            tv_state_sent: None,
+            tv_state_sent_at: None,
+            macro_steps: VecDeque::new(),
+            unmapped_codes: BTreeSet::new(),
+            box_woken_at: None,
+            box_reset_at: None,
+            code_last_seen_at: HashMap::new(),
+        }
+    }
 }
 
-fn send_sony_command(client: &mut Client, command: SonyCommand) {
-    client
-        .publish(
-            TV_REMOTE_COMMAND_TOPIC,
-            QoS::AtLeastOnce,
-            false,
-            to_variant_name(&command).unwrap(),
-        )
-        .unwrap();
+/// Bookkeeping for noticing when the remote or the TV state feed has gone quiet
+struct Activity {
+    started_at: Instant,
+    last_event_at: Option<Instant>,
+    last_tv_state_at: Option<Instant>,
+    tv_states: StateHistory,
+    /// Most recent MQTT connection error, and when it happened
+    last_error: Option<(String, Instant)>,
+    /// Whether the broker connection is up; periodic publishes are skipped while it isn't, so
+    /// they don't pile up in the queue
+    connected: bool,
+    /// Whether the subscriptions for the current connection have been queued; if the queue was
+    /// full they're retried on later ticks
+    subscribed: bool,
+    last_telemetry_at: Instant,
+    last_resync_at: Instant,
+    last_passthru_reassert_at: Instant,
+}
+
+impl Activity {
+    fn new(now: Instant) -> Activity {
+        Activity {
+            started_at: now,
+            last_event_at: None,
+            last_tv_state_at: None,
+            tv_states: StateHistory::new(now),
+            last_error: None,
+            connected: false,
+            subscribed: false,
+            last_telemetry_at: now,
+            last_resync_at: now,
+            last_passthru_reassert_at: now,
+        }
+    }
 }
 
-fn open_sony_app(client: &mut Client, app_name: &str) {
-    client
-        .publish(TV_MEDIA_PLAYER_APP_TOPIC, QoS::AtLeastOnce, false, app_name)
-        .unwrap();
+fn tv_state(state: &State) -> TvState {
+    if state.tv_unreachable {
+        TvState::Unreachable
+    } else if !state.power.is_on() {
+        TvState::Off
+    } else if state.on_home_input {
+        TvState::OnManaged
+    } else {
+        TvState::OnOther
+    }
 }
 
-fn handle_air_remote_event(event: &InputEvent, state: &State, client: &mut Client) {
-    println!("Input: {:?}", &event);
+fn send_passthru_flag_update(client: &mut dyn MqttSink, config: &Config, state: &State) {
+    let passthru = match (tv_state(state), config.unreachable_passthru) {
+        (TvState::OnManaged, _) => true,
+        (TvState::Unreachable, UnreachablePassthru::On) => true,
+        (TvState::Unreachable, UnreachablePassthru::LastKnown) => {
+            state.power.is_on() && state.on_home_input
+        }
+        _ => false,
+    };
+    send_passthru_flag(client, config, passthru);
+}
+
+fn send_passthru_flag(client: &mut dyn MqttSink, config: &Config, passthru: bool) {
+    if !config.manage_passthru {
+        return;
+    }
+    client.publish(
+        AIR_REMOTE_PASSTHRU_TOPIC,
+        QoS::AtLeastOnce,
+        false,
+        if passthru {
+            &config.passthru_on_payload
+        } else {
+            &config.passthru_off_payload
+        },
+    );
+}
+
+fn send_led_update(client: &mut dyn MqttSink, config: &Config, state: &State) {
+    let feedback = match tv_state(state) {
+        TvState::Off => config.led_tv_off,
+        TvState::OnManaged => config.led_home_input,
+        TvState::OnOther => config.led_other_input,
+        TvState::Unreachable => config.led_tv_unreachable,
+    };
+    client.publish(
+        AIR_REMOTE_LED_TOPIC,
+        QoS::AtLeastOnce,
+        false,
+        to_variant_name(&feedback).unwrap(),
+    );
+}
+
+/// Flash the LED right away so the user knows a press registered, however slow the TV is
+fn flash_dispatch_led(
+    event: &InputEvent,
+    state: &mut State,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    now: Instant,
+) {
+    let Some(feedback) = config.dispatch_led else {
+        return;
+    };
+    let flashes = event
+        .button_kind()
+        .is_some_and(|kind| config.dispatch_led_buttons.iter().any(|b| b == kind));
+    if !flashes {
+        return;
+    }
+    client.publish(
+        AIR_REMOTE_LED_TOPIC,
+        QoS::AtLeastOnce,
+        false,
+        to_variant_name(&feedback).unwrap(),
+    );
+    state.led_restore_at = Some(now + config.dispatch_led_time);
+}
+
+fn restore_led(state: &mut State, config: &Config, client: &mut dyn MqttSink, now: Instant) {
+    if state
+        .led_restore_at
+        .is_some_and(|restore_at| now >= restore_at)
+    {
+        state.led_restore_at = None;
+        send_led_update(client, config, state);
+    }
+}
+
+/// Publishes the latest TV state once it differs from what was last sent, but no more often
+/// than the configured interval
+fn send_tv_state(state: &mut State, config: &Config, client: &mut dyn MqttSink, now: Instant) {
+    let current = tv_state(state);
+    if state.tv_state_sent == Some(current) {
+        return;
+    }
+    let throttled = state
+        .tv_state_sent_at
+        .is_some_and(|sent_at| now - sent_at < config.state_publish_interval);
+    if throttled {
+        return;
+    }
+    client.publish(
+        MEDIATOR_TV_STATE_TOPIC,
+        QoS::AtLeastOnce,
+        true,
+        current.label(&config.managed_state_label),
+    );
+    state.tv_state_sent = Some(current);
+    state.tv_state_sent_at = Some(now);
+}
+
+fn send_usb_readiness_update(client: &mut dyn MqttSink, config: &Config, ready: bool) {
+    client.publish(
+        config.usb_readiness_topic.as_str(),
+        QoS::AtLeastOnce,
+        true,
+        if ready { "ON" } else { "OFF" },
+    );
+}
+
+fn handle_usb_readiness_event(
+    client: &mut dyn MqttSink,
+    config: &Config,
+    event: UsbReadinessEvent,
+) {
     match event {
-        InputEvent::PowerButton => {
-            send_ha_script_command(client, HA_SCRIPT_TOGGLE_TV_AND_DENNIS);
+        UsbReadinessEvent::Changed(false) => {
+            send_ha_script_command(client, config, HA_SCRIPT_NOTICE_DENNIS_USB_OFF);
+            send_usb_readiness_update(client, config, false);
+        }
+        UsbReadinessEvent::Changed(true) => {
+            send_ha_script_command(client, config, HA_SCRIPT_NOTICE_DENNIS_USB_ON);
+            send_usb_readiness_update(client, config, true);
+        }
+        UsbReadinessEvent::Unstable => {
+            println!("ERR: USB readiness is flapping");
+            send_ha_script_command(client, config, HA_SCRIPT_NOTICE_DENNIS_USB_UNSTABLE);
+        }
+    }
+}
+
+fn send_battery_level(client: &mut dyn MqttSink, percent: u8) {
+    client.publish(BATTERY_TOPIC, QoS::AtLeastOnce, true, &percent.to_string());
+}
+
+fn send_battery_discovery(client: &mut dyn MqttSink) {
+    let discovery = json!({
+        "name": "Air Remote Battery",
+        "unique_id": "air_remote_battery",
+        "state_topic": BATTERY_TOPIC,
+        "device_class": "battery",
+        "unit_of_measurement": "%",
+        "device": {
+            "identifiers": ["air_remote_mediator"],
+            "name": "Air Remote Mediator",
+            "sw_version": VERSION,
+        },
+    });
+    client.publish(
+        BATTERY_DISCOVERY_TOPIC,
+        QoS::AtLeastOnce,
+        true,
+        &discovery.to_string(),
+    );
+}
+
+fn send_ha_message(client: &mut dyn MqttSink, message: HaMessage) {
+    client.publish(&message.topic, QoS::AtLeastOnce, false, &message.payload);
+}
+
+fn send_ha_script_command(client: &mut dyn MqttSink, config: &Config, script_name: &str) {
+    let message = config
+        .ha_dialect
+        .script(&config.ha_native_topic_base, script_name);
+    send_ha_message(client, message);
+}
+
+fn send_sony_command(client: &mut dyn MqttSink, config: &Config, command: SonyCommand) {
+    send_variant_command(client, config, &command);
+}
+
+/// Sends a remote command named after an enum variant. One that doesn't serialize as a variant,
+/// like an untagged one, can't be named, and is logged and skipped.
+fn send_variant_command<T: Serialize + std::fmt::Debug>(
+    client: &mut dyn MqttSink,
+    config: &Config,
+    command: &T,
+) {
+    match to_variant_name(command) {
+        Ok(name) => {
+            let message = config
+                .ha_dialect
+                .remote_command(&config.ha_native_topic_base, name);
+            send_ha_message(client, message);
+        }
+        Err(e) => println!("ERR: Can't name remote command {:?}: {}", command, e),
+    }
+}
+
+fn send_raw_command(client: &mut dyn MqttSink, config: &Config, command: &str) {
+    if !config.allow_raw_commands {
+        println!(
+            "ERR: Refusing raw command {:?}, ALLOW_RAW_COMMANDS is off",
+            command
+        );
+        return;
+    }
+    println!("!!! Sending raw command {:?} to the TV", command);
+    let message = config
+        .ha_dialect
+        .remote_command(&config.ha_native_topic_base, command);
+    send_ha_message(client, message);
+}
+
+fn open_sony_app(client: &mut dyn MqttSink, config: &Config, app_name: &str) {
+    let message = config
+        .ha_dialect
+        .tv_app(&config.ha_native_topic_base, app_name);
+    send_ha_message(client, message);
+}
+
+/// Number keys go straight to the TV tuner for direct channel entry
+fn digit_command(key: u8) -> Option<SonyCommand> {
+    match key {
+        b'0' => Some(SonyCommand::Num0),
+        b'1' => Some(SonyCommand::Num1),
+        b'2' => Some(SonyCommand::Num2),
+        b'3' => Some(SonyCommand::Num3),
+        b'4' => Some(SonyCommand::Num4),
+        b'5' => Some(SonyCommand::Num5),
+        b'6' => Some(SonyCommand::Num6),
+        b'7' => Some(SonyCommand::Num7),
+        b'8' => Some(SonyCommand::Num8),
+        b'9' => Some(SonyCommand::Num9),
+        _ => None,
+    }
+}
+
+fn perform_action(
+    action: &Action,
+    state: &mut State,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    now: Instant,
+) {
+    println!("Action: {}", action);
+    match action {
+        Action::SonyCommand(command) => send_sony_command(client, config, *command),
+        Action::RawCommand(command) => send_raw_command(client, config, command),
+        Action::Script(script_name) => send_ha_script_command(client, config, script_name),
+        Action::OpenApp(app_name) => open_sony_app(client, config, app_name),
+        Action::AndroidApp(package) => open_android_app(client, config, package),
+        Action::BoxPower(true) => {
+            if !is_repeated_box_request(&mut state.box_woken_at, config, now) {
+                send_ha_script_command(client, config, &config.box_wake_script)
+            }
+        }
+        Action::BoxPower(false) => send_ha_script_command(client, config, &config.box_sleep_script),
+        Action::ResetBox => {
+            if !is_repeated_box_request(&mut state.box_reset_at, config, now) {
+                send_ha_script_command(client, config, &config.box_reset_script)
+            }
+        }
+        Action::CycleInput => cycle_input(state, config, client),
+        Action::GuestMode(enabled) => set_guest_mode(state, client, *enabled, now),
+        Action::ToggleGuestMode => {
+            let enabled = state.guest_mode_since.is_none();
+            set_guest_mode(state, client, enabled, now)
         }
+        Action::SleepTimer(minutes) => set_sleep_timer(state, client, *minutes, now),
+        Action::Power(on) => set_power(state, config, client, *on, now),
+        Action::HomeInput => select_home_input(state, config, client, false, now),
+        Action::Macro(steps) => start_macro(state, steps, now),
+        Action::Recover => start_macro(state, &config.recover_macro, now),
+        Action::AllOff => start_macro(state, &config.all_off_macro, now),
+        Action::Resync => resync(state, config, client),
+    }
+}
+
+/// Box wakes close together are taken to be repeats of the same request, and only the first one
+/// counts; likewise resets. Each is timed from the last of its own kind, so a reset right after a
+/// wake still goes through.
+fn is_repeated_box_request(last_at: &mut Option<Instant>, config: &Config, now: Instant) -> bool {
+    let repeat = last_at.is_some_and(|last_at| now - last_at < config.box_wake_dedup_window);
+    if repeat {
+        println!("Ignoring repeated box wake or reset");
+    } else {
+        *last_at = Some(now);
+    }
+    repeat
+}
+
+fn set_power(
+    state: &mut State,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    on: bool,
+    now: Instant,
+) {
+    let settled = if on { PowerState::On } else { PowerState::Off };
+    if state.power.state() == settled {
+        return;
+    }
+    // Toggling blind could just as well turn it back on
+    if state.tv_unreachable && !on {
+        println!("ERR: Not turning TV off while it's unreachable");
+        return;
+    }
+    toggle_power(state, config, client, now);
+}
+
+fn start_macro(state: &mut State, steps: &[MacroStep], now: Instant) {
+    if !state.macro_steps.is_empty() {
+        println!("Cancelling running macro");
+    }
+    let mut due_at = now;
+    state.macro_steps = steps
+        .iter()
+        .map(|step| {
+            due_at += Duration::from_millis(step.delay_ms);
+            (due_at, step.action.clone())
+        })
+        .collect();
+}
+
+fn run_macro_steps(state: &mut State, config: &Config, client: &mut dyn MqttSink, now: Instant) {
+    while state
+        .macro_steps
+        .front()
+        .is_some_and(|(due_at, _)| now >= *due_at)
+    {
+        let (_, action) = state.macro_steps.pop_front().unwrap();
+        perform_action(&action, state, config, client, now);
+    }
+}
+
+fn cycle_input(state: &mut State, config: &Config, client: &mut dyn MqttSink) {
+    if config.cycle_inputs.is_empty() {
+        println!("ERR: No inputs configured to cycle through");
+        return;
+    }
+    let next = state
+        .cycle_position
+        .map_or(0, |position| (position + 1) % config.cycle_inputs.len());
+    let choice = &config.cycle_inputs[next];
+    println!("Cycling to input {:?}", choice.title);
+    state.cycle_position = Some(next);
+    state.input_change_requested = true;
+    send_sony_command(client, config, choice.command);
+}
+
+fn set_guest_mode(state: &mut State, client: &mut dyn MqttSink, enabled: bool, now: Instant) {
+    println!("Guest mode {}", if enabled { "on" } else { "off" });
+    state.guest_mode_since = if enabled { Some(now) } else { None };
+    send_guest_mode(client, state);
+}
+
+fn send_guest_mode(client: &mut dyn MqttSink, state: &State) {
+    client.publish(
+        GUEST_MODE_TOPIC,
+        QoS::AtLeastOnce,
+        true,
+        if state.guest_mode_since.is_some() {
+            "ON"
+        } else {
+            "OFF"
+        },
+    );
+}
+
+fn expire_guest_mode(state: &mut State, config: &Config, client: &mut dyn MqttSink, now: Instant) {
+    let expired = match (state.guest_mode_since, config.guest_mode_timeout) {
+        (Some(since), Some(timeout)) => now - since >= timeout,
+        _ => false,
+    };
+    if expired {
+        set_guest_mode(state, client, false, now);
+    }
+}
+
+fn set_sleep_timer(state: &mut State, client: &mut dyn MqttSink, minutes: u64, now: Instant) {
+    if minutes == 0 {
+        println!("Sleep timer cancelled");
+        state.sleep_at = None;
+    } else {
+        let sleep_at = minutes
+            .checked_mul(60)
+            .and_then(|secs| now.checked_add(Duration::from_secs(secs)));
+        let Some(sleep_at) = sleep_at else {
+            println!("ERR: Sleep timer of {} minutes is too long", minutes);
+            return;
+        };
+        println!("Sleep timer set for {} minutes", minutes);
+        state.sleep_at = Some(sleep_at);
+    }
+    send_sleep_timer(client, state, now);
+}
+
+/// Publishes the minutes left on the sleep timer (rounded up, 0 when it isn't running) if changed
+fn send_sleep_timer(client: &mut dyn MqttSink, state: &mut State, now: Instant) {
+    let minutes = state.sleep_at.map_or(0, |sleep_at| {
+        let remaining = sleep_at.saturating_duration_since(now).as_secs();
+        remaining.div_ceil(60)
+    });
+    if state.sleep_minutes_sent == Some(minutes) {
+        return;
+    }
+    state.sleep_minutes_sent = Some(minutes);
+    client.publish(
+        SLEEP_TIMER_TOPIC,
+        QoS::AtLeastOnce,
+        true,
+        &minutes.to_string(),
+    );
+}
+
+fn run_sleep_timer(state: &mut State, config: &Config, client: &mut dyn MqttSink, now: Instant) {
+    let Some(sleep_at) = state.sleep_at else {
+        return;
+    };
+    if now >= sleep_at {
+        println!("Sleep timer ran out, turning TV off");
+        state.sleep_at = None;
+        set_power(state, config, client, false, now);
+    }
+}
+
+fn resync(state: &State, config: &Config, client: &mut dyn MqttSink) {
+    send_passthru_flag_update(client, config, state);
+    send_led_update(client, config, state);
+    // Statestream topics are retained, so subscribing again gets the broker to replay them
+    let topics: Vec<(&str, QoS)> = TV_STATUS_TOPICS
+        .iter()
+        .copied()
+        .chain(config.tv_app_topic.as_deref())
+        .map(|topic| (topic, QoS::AtLeastOnce))
+        .collect();
+    for (topic, _) in &topics {
+        client.unsubscribe(topic);
+    }
+    client.subscribe(&topics);
+}
+
+fn send_command_ack(
+    client: &mut dyn MqttSink,
+    config: &Config,
+    topic: &str,
+    id: Option<&serde_json::Value>,
+) {
+    let Some(ack_topic) = &config.command_ack_topic else {
+        return;
+    };
+    let ack = json!({
+        "topic": topic,
+        "id": id,
+    });
+    client.publish(ack_topic, QoS::AtLeastOnce, false, &ack.to_string());
+}
+
+fn volume_goes_to_avr(state: &State, config: &Config) -> bool {
+    state
+        .current_input
+        .as_ref()
+        .is_some_and(|input| config.avr_inputs.contains(input))
+}
+
+/// Runs a volume script as many times as one press should step on the current input
+fn send_volume_steps(client: &mut dyn MqttSink, config: &Config, state: &State, script: &str) {
+    let steps = state
+        .current_input
+        .as_ref()
+        .and_then(|input| config.volume_steps.get(input).copied())
+        .unwrap_or(if volume_goes_to_avr(state, config) {
+            config.avr_volume_steps
+        } else {
+            1
+        });
+    for _ in 0..steps {
+        send_ha_script_command(client, config, script);
+    }
+}
+
+fn open_android_app(client: &mut dyn MqttSink, config: &Config, package: &str) {
+    let message = config.ha_dialect.android_app(
+        &config.ha_native_topic_base,
+        &config.android_app_topic,
+        package,
+    );
+    send_ha_message(client, message);
+}
+
+fn mapped_action<'a>(event: &InputEvent, config: &'a Config) -> Option<&'a Action> {
+    match event {
+        InputEvent::ConsumerCode { data } => config.consumer_code_actions.get(data),
+        InputEvent::KeyCode { data } => config.key_code_actions.get(data),
+        _ => None,
+    }
+}
+
+fn volume_at_cap(state: &State, config: &Config) -> bool {
+    match (state.volume, config.max_volume) {
+        (Some(volume), Some(max_volume)) => volume >= max_volume,
+        _ => false,
+    }
+}
+
+fn send_volume_capped(client: &mut dyn MqttSink, state: &State) {
+    client.publish(
+        VOLUME_CAPPED_TOPIC,
+        QoS::AtLeastOnce,
+        false,
+        &json!({ "volume": state.volume }).to_string(),
+    );
+}
+
+/// Adds a code to the retained list of codes the remote sent that nothing handles
+fn note_unmapped(
+    state: &mut State,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    kind: &str,
+    code: u8,
+) {
+    let Some(topic) = &config.unmapped_codes_topic else {
+        return;
+    };
+    if state
+        .unmapped_codes
+        .insert(format!("{} {:#04X}", kind, code))
+    {
+        let codes: Vec<&String> = state.unmapped_codes.iter().collect();
+        client.publish(topic, QoS::AtLeastOnce, true, &json!(codes).to_string());
+    }
+}
+
+fn toggle_power(state: &mut State, config: &Config, client: &mut dyn MqttSink, now: Instant) {
+    // Bravia entities also go unavailable in deep standby, and HA can often still wake the TV
+    if state.tv_unreachable {
+        println!("TV is unreachable, toggling its power anyway");
+    }
+    if state.power.press(now) {
+        send_ha_script_command(client, config, HA_SCRIPT_TOGGLE_TV_AND_DENNIS);
+    }
+}
+
+/// A power press with no second press in the double press window was a single press
+fn finish_power_press(state: &mut State, config: &Config, client: &mut dyn MqttSink, now: Instant) {
+    if state
+        .power_pressed_at
+        .is_some_and(|pressed_at| now - pressed_at >= config.power_double_press_window)
+    {
+        state.power_pressed_at = None;
+        toggle_power(state, config, client, now);
+    }
+}
+
+fn is_disabled(event: &InputEvent, config: &Config) -> bool {
+    match event {
+        InputEvent::ConsumerCode { data } => config.disabled_consumer_codes.contains(data),
+        InputEvent::KeyCode { data } => config.disabled_key_codes.contains(data),
+        InputEvent::PowerButton => config.disable_power_button,
+        _ => false,
+    }
+}
+
+fn is_suppressed_repeat(
+    event: &InputEvent,
+    state: &mut State,
+    config: &Config,
+    now: Instant,
+) -> bool {
+    let (rule, key) = match event {
+        InputEvent::ConsumerCode { data } => (config.consumer_code_repeat.get(data), ('C', *data)),
+        InputEvent::KeyCode { data } => (config.key_code_repeat.get(data), ('K', *data)),
+        _ => return false,
+    };
+    let Some(rule) = rule else {
+        return false;
+    };
+    let interval = Duration::from_millis(rule.interval_ms);
+    let suppressed = state
+        .code_last_seen_at
+        .get(&key)
+        .is_some_and(|seen_at| now - *seen_at < interval);
+    // Non-repeating buttons stay quiet until the code stops arriving, so the clock restarts on
+    // every repeat; repeating ones are timed from the last repeat acted on
+    if !suppressed || !rule.repeat {
+        state.code_last_seen_at.insert(key, now);
+    }
+    suppressed
+}
+
+fn handle_air_remote_event(
+    event: &InputEvent,
+    state: &mut State,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    now: Instant,
+) {
+    println!("Input: {:?}", &event);
+    if is_disabled(event, config) {
+        println!("Ignoring disabled button");
+        return;
+    }
+    if is_suppressed_repeat(event, state, config, now) {
+        println!("Ignoring repeat");
+        return;
+    }
+    flash_dispatch_led(event, state, config, client, now);
+    if let InputEvent::ConsumerCode { data } = event {
+        if config.input_change_codes.contains(data) {
+            state.input_change_requested = true;
+        }
+    }
+    if let Some(action) = mapped_action(event, config) {
+        perform_action(action, state, config, client, now);
+        return;
+    }
+    match event {
+        InputEvent::PowerButton => match &config.power_double_press_action {
+            Some(action) => {
+                finish_power_press(state, config, client, now);
+                if state.power_pressed_at.take().is_some() {
+                    println!("Power double press");
+                    perform_action(action, state, config, client, now);
+                } else {
+                    state.power_pressed_at = Some(now);
+                }
+            }
+            None => toggle_power(state, config, client, now),
+        },
         InputEvent::ConsumerCode { data } => match *data {
-            CONSUMER_CODE_VOLUME_DOWN => send_ha_script_command(client, HA_SCRIPT_TV_VOLUME_DOWN),
-            CONSUMER_CODE_VOLUME_UP => send_ha_script_command(client, HA_SCRIPT_TV_VOLUME_UP),
-            CONSUMER_CODE_CHANNEL => send_sony_command(client, SonyCommand::Input),
-            CONSUMER_CODE_MEDIA_SELECT_HOME => open_sony_app(client, "HALauncher"),
-            CONSUMER_CODE_MENU_ESCAPE => send_sony_command(client, SonyCommand::Return),
+            CONSUMER_CODE_VOLUME_DOWN => {
+                if volume_goes_to_avr(state, config) {
+                    send_volume_steps(client, config, state, &config.avr_volume_down_script)
+                } else {
+                    send_volume_steps(client, config, state, HA_SCRIPT_TV_VOLUME_DOWN)
+                }
+            }
+            CONSUMER_CODE_VOLUME_UP => {
+                if volume_goes_to_avr(state, config) {
+                    send_volume_steps(client, config, state, &config.avr_volume_up_script)
+                } else if volume_at_cap(state, config) {
+                    println!("Volume is already at the cap");
+                    send_volume_capped(client, state);
+                } else {
+                    send_volume_steps(client, config, state, HA_SCRIPT_TV_VOLUME_UP)
+                }
+            }
+            CONSUMER_CODE_CLOSED_CAPTION => {
+                send_sony_command(client, config, SonyCommand::SubTitle)
+            }
+            CONSUMER_CODE_CHANNEL => send_sony_command(client, config, SonyCommand::Input),
+            CONSUMER_CODE_CHANNEL_INCREMENT => {
+                send_sony_command(client, config, SonyCommand::ChannelUp)
+            }
+            CONSUMER_CODE_CHANNEL_DECREMENT => {
+                send_sony_command(client, config, SonyCommand::ChannelDown)
+            }
+            CONSUMER_CODE_MEDIA_SELECT_HOME => match &config.home_button_on_home_input {
+                Some(action) if tv_state(state) == TvState::OnManaged => {
+                    perform_action(action, state, config, client, now)
+                }
+                _ => open_sony_app(client, config, "HALauncher"),
+            },
+            CONSUMER_CODE_MENU_ESCAPE => send_sony_command(client, config, SonyCommand::Return),
             CONSUMER_CODE_PLAY_PAUSE => {
-                if !state.dennis_is_current_input {
-                    send_sony_command(client, SonyCommand::Pause)
+                if !state.on_home_input {
+                    send_sony_command(client, config, SonyCommand::Pause)
                 }
             }
             _ => {
                 println!("Unhandled consumer code: {:#04X}", data);
+                note_unmapped(state, config, client, "consumer", *data);
             }
         },
         InputEvent::KeyCode { data } => match *data {
-            HID_KEY_ARROW_UP => send_sony_command(client, SonyCommand::Up),
-            HID_KEY_ARROW_DOWN => send_sony_command(client, SonyCommand::Down),
-            HID_KEY_ARROW_LEFT => send_sony_command(client, SonyCommand::Left),
-            HID_KEY_ARROW_RIGHT => send_sony_command(client, SonyCommand::Right),
-            _ => println!("Unhandled key code: {:#04X}", data),
+            HID_KEY_ARROW_UP => send_sony_command(client, config, SonyCommand::Up),
+            HID_KEY_ARROW_DOWN => send_sony_command(client, config, SonyCommand::Down),
+            HID_KEY_ARROW_LEFT => send_sony_command(client, config, SonyCommand::Left),
+            HID_KEY_ARROW_RIGHT => send_sony_command(client, config, SonyCommand::Right),
+            HID_KEY_ENTER => send_sony_command(client, config, SonyCommand::Confirm),
+            HID_KEY_ESCAPE | HID_KEY_BACKSPACE => {
+                send_sony_command(client, config, SonyCommand::Return)
+            }
+            HID_KEY_HOME => send_sony_command(client, config, SonyCommand::Home),
+            HID_KEY_END => send_sony_command(client, config, SonyCommand::Exit),
+            HID_KEY_PAGE_UP => send_sony_command(client, config, SonyCommand::ChannelUp),
+            HID_KEY_PAGE_DOWN => send_sony_command(client, config, SonyCommand::ChannelDown),
+            _ => {
+                println!("Unhandled key code: {:#04X}", data);
+                note_unmapped(state, config, client, "key", *data);
+            }
         },
         InputEvent::OkButton => {
-            send_sony_command(client, SonyCommand::Confirm);
+            if config.ok_long_press {
+                state.ok_pressed_at = Some(now);
+            } else {
+                send_sony_command(client, config, SonyCommand::Confirm);
+            }
         }
-        InputEvent::UsbReadinessStateChange { data } => match *data {
-            b'N' => send_ha_script_command(client, HA_SCRIPT_NOTICE_DENNIS_USB_OFF),
-            b'Y' => send_ha_script_command(client, HA_SCRIPT_NOTICE_DENNIS_USB_ON),
-            _ => println!("Unhandled USB readiness state: {:#04X}", data),
+        InputEvent::OkButtonRelease => {
+            if let Some(pressed_at) = state.ok_pressed_at.take() {
+                if now - pressed_at >= config.ok_long_press_threshold {
+                    send_sony_command(client, config, config.ok_long_press_command);
+                } else {
+                    send_sony_command(client, config, SonyCommand::Confirm);
+                }
+            }
+        }
+        InputEvent::UsbReadinessStateChange { data } => {
+            let ready = match *data {
+                b'N' => false,
+                b'Y' => true,
+                _ => {
+                    println!("Unhandled USB readiness state: {:#04X}", data);
+                    return;
+                }
+            };
+            if let Some(event) = state.usb_readiness.report(ready, now) {
+                handle_usb_readiness_event(client, config, event);
+            }
+        }
+        InputEvent::AsciiKey { data } => match digit_command(*data) {
+            Some(command) => send_sony_command(client, config, command),
+            None => {
+                println!("Unhandled ascii key: {:#04X}", data);
+                note_unmapped(state, config, client, "ascii", *data);
+            }
         },
-        InputEvent::AsciiKey { .. } | InputEvent::NetworkConnected => {
+        InputEvent::BatteryLevel { data } => send_battery_level(client, *data),
+        InputEvent::NetworkConnected => {
             println!("Event: {:?}", event);
         }
     }
 }
 
+fn guard_home_input(
+    was_on_home_input: bool,
+    state: &mut State,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    now: Instant,
+) {
+    if state.on_home_input || tv_state(state) != TvState::OnOther {
+        state.input_change_requested = false;
+        return;
+    }
+    if !config.keep_home_input || !was_on_home_input || state.guest_mode_since.is_some() {
+        return;
+    }
+    if state
+        .settling_until
+        .is_some_and(|settling_until| now < settling_until)
+    {
+        println!("Left home input while TV is still settling");
+    } else if state.input_change_requested {
+        println!("Left home input at user request");
+    } else {
+        println!("TV drifted off home input, switching back");
+        select_home_input(state, config, client, false, now);
+    }
+}
+
+fn select_home_input(
+    state: &mut State,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    retry: bool,
+    now: Instant,
+) {
+    send_sony_command(client, config, config.home_input_command);
+    state.home_input_check = Some(InputCheck {
+        due_at: now + config.command_verify_delay,
+        retried: retry,
+    });
+}
+
+fn verify_home_input(state: &mut State, config: &Config, client: &mut dyn MqttSink, now: Instant) {
+    let Some(check) = &state.home_input_check else {
+        return;
+    };
+    if now < check.due_at {
+        return;
+    }
+    let retried = check.retried;
+    state.home_input_check = None;
+    if tv_state(state) != TvState::OnOther || state.guest_mode_since.is_some() {
+        return;
+    }
+    println!("ERR: TV didn't switch to home input");
+    if config.retry_ignored_commands && !retried {
+        println!("Retrying home input switch");
+        select_home_input(state, config, client, true, now);
+    }
+}
+
+/// Everything known about the TV, as a single snapshot
+fn tv_status(state: &State) -> serde_json::Value {
+    json!({
+        "power": state.power.is_on(),
+        "power_state": state.power.state(),
+        "reachable": !state.tv_unreachable,
+        "input": state.current_input,
+        "app": state.current_app,
+        "volume": state.volume,
+        "muted": state.muted,
+    })
+}
+
+fn telemetry(
+    state: &State,
+    activity: &Activity,
+    config: &Config,
+    now: Instant,
+) -> serde_json::Value {
+    let secs_since = |instant: Option<Instant>| instant.map(|i| (now - i).as_secs());
+    json!({
+        "version": VERSION,
+        "uptime_secs": (now - activity.started_at).as_secs(),
+        "last_event_secs_ago": secs_since(activity.last_event_at),
+        "last_tv_state_secs_ago": secs_since(activity.last_tv_state_at),
+        "tv": tv_status(state),
+        "tv_states": activity.tv_states.to_json(&config.managed_state_label, now),
+        "guest_mode": state.guest_mode_since.is_some(),
+        "sleep_timer_minutes": state.sleep_minutes_sent,
+    })
+}
+
+fn send_telemetry(
+    client: &mut dyn MqttSink,
+    state: &State,
+    activity: &Activity,
+    config: &Config,
+    now: Instant,
+) {
+    client.publish(
+        TELEMETRY_TOPIC,
+        QoS::AtMostOnce,
+        false,
+        &telemetry(state, activity, config, now).to_string(),
+    );
+}
+
+/// Everything in one document, for remote troubleshooting
+fn send_status(
+    client: &mut dyn MqttSink,
+    state: &State,
+    activity: &Activity,
+    config: &Config,
+    now: Instant,
+) {
+    let mut status = telemetry(state, activity, config, now);
+    status["config"] = serde_json::to_value(config).unwrap();
+    status["last_error"] = match &activity.last_error {
+        Some((error, at)) => json!({ "error": error, "secs_ago": (now - *at).as_secs() }),
+        None => serde_json::Value::Null,
+    };
+    status["commands"] = client.command_stats();
+    status["running_macro_steps"] = json!(state.macro_steps.len());
+    status["unmapped_codes"] = json!(state.unmapped_codes);
+    client.publish(
+        &config.status_topic,
+        QoS::AtLeastOnce,
+        false,
+        &status.to_string(),
+    );
+}
+
+fn handle_mqtt_message(
+    topic: &str,
+    payload: &[u8],
+    state: &mut State,
+    activity: &mut Activity,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    now: Instant,
+) {
+    let payload = match std::str::from_utf8(payload) {
+        Ok(payload) => payload,
+        Err(e) => {
+            println!("ERR: Non-UTF8 payload on {:?}: {}", topic, e);
+            return;
+        }
+    };
+    match topic {
+        AIR_REMOTE_TOPIC => match serde_json::from_str::<InputEvent>(payload) {
+            Ok(event) => {
+                activity.last_event_at = Some(now);
+                handle_air_remote_event(&event, state, config, client, now);
+            }
+            Err(e) => println!("ERR: Unparseable air remote event {:?}: {}", payload, e),
+        },
+        TV_STATE_TOPIC => {
+            activity.last_tv_state_at = Some(now);
+            let was_on = state.power.is_on();
+            // HA also reports `unknown` while the TV is on, so only `unavailable` means it's gone
+            state.tv_unreachable = payload == "unavailable";
+            if !state.tv_unreachable {
+                state.power.report(payload != "off", now);
+            }
+            if !was_on && state.power.is_on() {
+                state.settling_until = Some(now + config.settling_window);
+            }
+            activity.tv_states.update(tv_state(state), now);
+            send_passthru_flag_update(client, config, state);
+            send_led_update(client, config, state);
+            println!("State: {:?}", state);
+        }
+        TV_INPUT_TOPIC => {
+            activity.last_tv_state_at = Some(now);
+            let was_on_home_input = state.on_home_input;
+            state.current_input = serde_json::from_str(payload).ok();
+            state.on_home_input = state
+                .current_input
+                .as_ref()
+                .is_some_and(|input| config.home_inputs.contains(input));
+            if let Some(position) = config
+                .cycle_inputs
+                .iter()
+                .position(|choice| state.current_input.as_ref() == Some(&choice.title))
+            {
+                state.cycle_position = Some(position);
+            }
+            activity.tv_states.update(tv_state(state), now);
+            guard_home_input(was_on_home_input, state, config, client, now);
+            send_passthru_flag_update(client, config, state);
+            send_led_update(client, config, state);
+            println!("State: {:?}", state);
+        }
+        TV_VOLUME_TOPIC => {
+            state.volume = serde_json::from_str(payload).ok();
+            let over_cap = match (state.volume, config.max_volume) {
+                (Some(volume), Some(max_volume)) => volume > max_volume,
+                _ => false,
+            };
+            if over_cap {
+                println!("Volume is over the cap, turning it down");
+                send_volume_capped(client, state);
+                send_ha_script_command(client, config, HA_SCRIPT_TV_VOLUME_DOWN);
+            }
+        }
+        TV_MUTED_TOPIC => {
+            state.muted = serde_json::from_str(payload).ok();
+        }
+        topic if config.tv_app_topic.as_deref() == Some(topic) => {
+            state.current_app = serde_json::from_str(payload).ok();
+            println!("TV app: {:?}", state.current_app);
+        }
+        topic if topic == config.status_request_topic => {
+            send_status(client, state, activity, config, now);
+        }
+        topic if topic == config.command_topic => {
+            // Besides JSON, a bare compact action like `script:movie_mode` is accepted
+            let command = if payload.trim_start().starts_with('{') {
+                serde_json::from_str(payload)
+            } else {
+                Ok(action::compact_to_json(payload.trim()))
+            };
+            let parsed = command.and_then(|mut command| {
+                action::resolve_aliases(&mut command, &config.command_aliases);
+                serde_json::from_value::<IncomingCommand>(command)
+            });
+            match parsed {
+                Ok(command) => {
+                    perform_action(&command.action, state, config, client, now);
+                    send_command_ack(client, config, topic, command.id.as_ref());
+                }
+                Err(e) => println!("ERR: Unparseable command {:?}: {}", payload, e),
+            }
+        }
+        topic => match config.command_topics.get(topic) {
+            Some(action) => {
+                let request: CommandRequest = if payload.trim().is_empty() {
+                    CommandRequest::default()
+                } else {
+                    serde_json::from_str(payload).unwrap_or_else(|e| {
+                        println!("ERR: Unparseable command payload {:?}: {}", payload, e);
+                        CommandRequest::default()
+                    })
+                };
+                perform_action(action, state, config, client, now);
+                send_command_ack(client, config, topic, request.id.as_ref());
+            }
+            None => println!("ERR: Message from unknown topic {:?}", topic),
+        },
+    }
+}
+
+/// A message as it arrives from the broker
+fn handle_incoming(
+    message: &rumqttc::Publish,
+    state: &mut State,
+    activity: &mut Activity,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    now: Instant,
+) {
+    // A retained command would otherwise be redone on every reconnect
+    if message.retain && is_command_topic(&message.topic, config) {
+        println!("Ignoring retained command on {}", message.topic);
+        return;
+    }
+    handle_mqtt_message(
+        &message.topic,
+        &message.payload,
+        state,
+        activity,
+        config,
+        client,
+        now,
+    );
+}
+
+/// Everything done on a timer rather than in response to a message
+fn tick(
+    state: &mut State,
+    activity: &mut Activity,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    now: Instant,
+) {
+    verify_home_input(state, config, client, now);
+    expire_guest_mode(state, config, client, now);
+    run_sleep_timer(state, config, client, now);
+    restore_led(state, config, client, now);
+    run_macro_steps(state, config, client, now);
+    finish_power_press(state, config, client, now);
+    send_tv_state(state, config, client, now);
+    if let Some(event) = state.usb_readiness.poll(now) {
+        handle_usb_readiness_event(client, config, event);
+    }
+
+    if !activity.connected {
+        return;
+    }
+
+    // Without these the mediator would hear nothing until the next reconnect
+    if !activity.subscribed {
+        activity.subscribed = subscribe_all(client, config);
+    }
+    // Caught up on reconnect, so the countdown doesn't need queueing meanwhile
+    send_sleep_timer(client, state, now);
+
+    if now - activity.last_telemetry_at >= TELEMETRY_INTERVAL {
+        send_telemetry(client, state, activity, config, now);
+        activity.last_telemetry_at = now;
+    }
+
+    if let Some(interval) = config.resync_interval {
+        if now - activity.last_resync_at >= interval {
+            perform_action(&Action::Resync, state, config, client, now);
+            activity.last_resync_at = now;
+        }
+    }
+
+    if let Some(interval) = config.passthru_reassert_interval {
+        if now - activity.last_passthru_reassert_at >= interval {
+            send_passthru_flag_update(client, config, state);
+            activity.last_passthru_reassert_at = now;
+        }
+    }
+}
+
+/// Whether anything already started is still due to happen soon. A running sleep timer
+/// doesn't count, since it can be hours away.
+fn has_pending_work(state: &State) -> bool {
+    !state.macro_steps.is_empty()
+        || state.usb_readiness.is_settling()
+        || state.power_pressed_at.is_some()
+        || state.led_restore_at.is_some()
+        || state.home_input_check.is_some()
+}
+
+fn is_command_topic(topic: &str, config: &Config) -> bool {
+    topic == config.command_topic || config.command_topics.contains_key(topic)
+}
+
+/// Returns whether the subscriptions were queued
+fn subscribe_all(client: &mut dyn MqttSink, config: &Config) -> bool {
+    let mut topics = vec![(AIR_REMOTE_TOPIC, QoS::AtMostOnce)];
+    topics.extend(
+        TV_STATUS_TOPICS
+            .iter()
+            .copied()
+            .chain(config.tv_app_topic.as_deref())
+            .chain([&config.command_topic, &config.status_request_topic].map(String::as_str))
+            .chain(config.command_topics.keys().map(String::as_str))
+            .map(|topic| (topic, QoS::AtLeastOnce)),
+    );
+    client.subscribe(&topics)
+}
+
+fn describe_connection_error(error: &ConnectionError) -> &'static str {
+    match error {
+        ConnectionError::Io(e) => match e.kind() {
+            io::ErrorKind::ConnectionRefused => "broker not accepting connections",
+            io::ErrorKind::NotFound | io::ErrorKind::AddrNotAvailable => "broker address not found",
+            io::ErrorKind::PermissionDenied => "permission denied",
+            io::ErrorKind::TimedOut => "timed out",
+            _ => "I/O error",
+        },
+        ConnectionError::ConnectionRefused(_) => "broker rejected the connection",
+        ConnectionError::NetworkTimeout | ConnectionError::FlushTimeout => "timed out",
+        _ => "connection lost",
+    }
+}
+
+fn run_shutdown_action(config: &Config, client: &mut dyn MqttSink) {
+    match config.shutdown_action {
+        ShutdownAction::None => {}
+        ShutdownAction::PassthruOff => {
+            println!("Turning passthru off before shutdown");
+            send_passthru_flag(client, config, false);
+        }
+        ShutdownAction::SelectHome => {
+            println!("Selecting home input before shutdown");
+            send_sony_command(client, config, config.home_input_command);
+        }
+    }
+}
+
+/// Disconnect once everything already published has gone out, or give up after a while
+fn flush_and_disconnect(client: &mut Client, events: &mut dyn MqttSource) {
+    if client.try_disconnect().is_err() {
+        println!("ERR: MQTT queue full, not waiting for it to flush before shutdown");
+        return;
+    }
+    let deadline = Instant::now() + SHUTDOWN_FLUSH_TIMEOUT;
+    while let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+        match events.recv_timeout(wait) {
+            Ok(Ok(Event::Outgoing(Outgoing::Disconnect))) | Ok(Err(_)) => return,
+            Err(RecvTimeoutError::Disconnected) => return,
+            _ => {}
+        }
+    }
+    println!("ERR: Timed out flushing MQTT before shutdown");
+}
+
+/// Drive the MQTT event loop on its own thread, waiting between reconnect attempts, and pass
+/// along what it reports. Polling it from the main loop instead would cut a slow connect short
+/// on every tick, so it would never finish connecting.
+/// Drives the connection on its own thread. If that crashes it's restarted within the budget;
+/// past it the thread ends, which the main loop sees as the connection gone.
+fn spawn_connection_thread(
+    mut connection: Connection,
+    mut reconnect_backoff: Backoff,
+    mut restart_budget: RestartBudget,
+) -> Receiver<Notification> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        let pumped = panic::catch_unwind(AssertUnwindSafe(|| {
+            pump_connection(&mut connection, &mut reconnect_backoff, &sender)
+        }));
+        if pumped.is_ok() {
+            return;
+        }
+        if !restart_budget.allow(Instant::now()) {
+            println!("ERR: MQTT connection thread keeps crashing, giving up");
+            return;
+        }
+        println!("ERR: MQTT connection thread crashed, restarting it");
+    });
+    receiver
+}
+
+/// Passes on notifications until nobody is listening for them
+fn pump_connection(
+    connection: &mut Connection,
+    reconnect_backoff: &mut Backoff,
+    sender: &mpsc::Sender<Notification>,
+) {
+    for notification in connection.iter() {
+        let failed = notification.is_err();
+        if let Ok(Incoming(ConnAck(_))) = notification {
+            reconnect_backoff.reset();
+        }
+        if sender.send(notification).is_err() {
+            return;
+        }
+        if failed {
+            thread::sleep(reconnect_backoff.next_delay());
+        }
+    }
+}
+
+fn action_reporter<S: MqttSink>(sink: S, config: &Config) -> ActionReporter<S> {
+    let command_topics = config
+        .ha_dialect
+        .command_topics(&config.ha_native_topic_base, &config.android_app_topic);
+    ActionReporter::new(sink, config.actions_topic.clone(), command_topics)
+}
+
+/// A few lines on how this deployment is set up, to catch a wrong setting before a button
+/// misbehaves. `--check-config` gives the whole picture.
+fn log_startup_summary(config: &Config) {
+    println!(
+        "MQTT broker {}:{} ({}), HA dialect {:?}",
+        MQTT_BROKER_HOST,
+        config.mqtt_port,
+        match (&config.mqtt_ca_file, &config.mqtt_client_cert_file) {
+            (None, _) => "plain",
+            (Some(_), None) => "TLS",
+            (Some(_), Some(_)) => "TLS with client certificate",
+        },
+        config.ha_dialect,
+    );
+    println!(
+        "Home inputs {:?}, keep home input {}, guest mode timeout {:?}",
+        config.home_inputs, config.keep_home_input, config.guest_mode_timeout,
+    );
+    let mut mappings: Vec<String> = config
+        .consumer_code_actions
+        .iter()
+        .map(|(code, action)| format!("consumer {:#04X} -> {:?}", code, action))
+        .chain(
+            config
+                .key_code_actions
+                .iter()
+                .map(|(code, action)| format!("key {:#04X} -> {:?}", code, action)),
+        )
+        .chain(
+            config
+                .command_topics
+                .iter()
+                .map(|(topic, action)| format!("{} -> {:?}", topic, action)),
+        )
+        .collect();
+    mappings.sort();
+    if mappings.is_empty() {
+        println!("No buttons or topics mapped to actions");
+    } else {
+        println!("Mapped: {}", mappings.join(", "));
+    }
+}
+
+/// Sleep in short steps so a shutdown signal isn't held up, returning whether the whole wait
+/// went by without one
+fn wait_unless_shutdown(shutdown_requested: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !shutdown_requested.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep(TICK_INTERVAL.min(deadline - now));
+    }
+    false
+}
+
+fn exit_if_invalid(config: &Config) {
+    if let Err(errors) = config.validate() {
+        for error in errors {
+            println!("ERR: {}", error);
+        }
+        die("Invalid config");
+    }
+}
+
+/// Value following `flag` on the command line, if the flag was given
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    let position = args.iter().position(|arg| arg == flag)?;
+    let value = args.get(position + 1);
+    if value.is_none() {
+        die(&format!("{} needs a file path", flag));
+    }
+    value.cloned()
+}
+
+/// Feed a recording back through the message handling, with the original timing, without
+/// publishing anything
+fn replay(path: &str, config: &Config) {
+    let messages = recording::read_recording(path)
+        .unwrap_or_else(|e| die(&format!("Can't read recording {}: {}", path, e)));
+    let mut state = State::new(config);
+    let mut activity = Activity::new(Instant::now());
+    activity.connected = true;
+    let mut sink = action_reporter(DryRunSink, config);
+    let started_at = Instant::now();
+
+    println!("Replaying {} messages from {}", messages.len(), path);
+    for message in messages {
+        let due_at = started_at + Duration::from_millis(message.at_ms);
+        while let Some(wait) = due_at.checked_duration_since(Instant::now()) {
+            tick(&mut state, &mut activity, config, &mut sink, Instant::now());
+            thread::sleep(wait.min(TICK_INTERVAL));
+        }
+        handle_incoming(
+            &message.to_publish(),
+            &mut state,
+            &mut activity,
+            config,
+            &mut sink,
+            Instant::now(),
+        );
+        tick(&mut state, &mut activity, config, &mut sink, Instant::now());
+    }
+    // Let whatever the last messages started, like a macro or a USB readiness change, play out
+    while has_pending_work(&state) {
+        thread::sleep(TICK_INTERVAL);
+        tick(&mut state, &mut activity, config, &mut sink, Instant::now());
+    }
+}
+
+/// Why the main loop stopped
+enum Stop {
+    Shutdown,
+    Disconnected,
+}
+
+/// Handle everything from the broker, and the timers in between, until asked to stop or the
+/// connection is gone for good
+fn run(
+    events: &mut dyn MqttSource,
+    client: &mut dyn MqttSink,
+    state: &mut State,
+    activity: &mut Activity,
+    config: &Config,
+    clock: &dyn Clock,
+    shutdown_requested: &AtomicBool,
+) -> Stop {
+    loop {
+        if shutdown_requested.load(Ordering::Relaxed) {
+            return Stop::Shutdown;
+        }
+        match events.recv_timeout(TICK_INTERVAL) {
+            Ok(notification) => {
+                handle_notification(notification, state, activity, config, client, clock.now())
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Stop::Disconnected,
+        }
+        tick(state, activity, config, client, clock.now());
+    }
+}
+
+fn handle_notification(
+    notification: Notification,
+    state: &mut State,
+    activity: &mut Activity,
+    config: &Config,
+    client: &mut dyn MqttSink,
+    now: Instant,
+) {
+    match notification {
+        Ok(Incoming(Publish(message))) => {
+            handle_incoming(&message, state, activity, config, client, now);
+        }
+        Ok(Incoming(ConnAck(_))) => {
+            // Sessions are clean, so subscriptions have to be redone on every reconnect
+            println!("Connected to MQTT broker");
+            activity.connected = true;
+            activity.subscribed = subscribe_all(client, config);
+            send_battery_discovery(client);
+            send_guest_mode(client, state);
+            state.sleep_minutes_sent = None;
+            send_sleep_timer(client, state, now);
+            state.tv_state_sent = None;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            let error = format!("MQTT {}: {}", describe_connection_error(&e), e);
+            println!("ERR: {}", error);
+            activity.last_error = Some((error, now));
+            activity.connected = false;
+            activity.subscribed = false;
+        }
+    }
+}
+
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.iter().any(|arg| arg == "--version") {
+        println!("air-remote-mediator {}", VERSION);
+        return;
+    }
+
+    let config = Config::from_env();
+
+    if args.iter().any(|arg| arg == "--check-config") {
+        println!("{}", serde_json::to_string_pretty(&config).unwrap());
+        exit_if_invalid(&config);
+        return;
+    }
+
+    if let Some(path) = flag_value(&args, "--replay") {
+        replay(&path, &config);
+        return;
+    }
+    exit_if_invalid(&config);
+
+    let recorder = flag_value(&args, "--record").map(|path| {
+        Recorder::create(&path)
+            .unwrap_or_else(|e| die(&format!("Can't open recording file {}: {}", path, e)))
+    });
+
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown_requested))
+            .expect("Registering signal handler");
+    }
+
+    if let Some(delay) = config.startup_delay {
+        println!("Waiting {}s before connecting", delay.as_secs_f64());
+        if !wait_unless_shutdown(&shutdown_requested, delay) {
+            println!("Shutting down");
+            return;
+        }
+    }
+
     let mut mqtt_options =
-        MqttOptions::new("air-remote-mediator", "mqtt.sinclair.pipsimon.com", 1883);
-    mqtt_options.set_credentials(
-        "lcars",
-        env::var("MQTT_PASS").expect("Need env var MQTT_PASS"),
-    );
+        MqttOptions::new("air-remote-mediator", MQTT_BROKER_HOST, config.mqtt_port);
+    if let Some(mqtt_pass) = &config.mqtt_pass {
+        mqtt_options.set_credentials("lcars", mqtt_pass.as_str());
+    }
+    if let Some(transport) = mqtt_tls::tls_transport(&config)
+        .unwrap_or_else(|e| die(&format!("Setting up MQTT TLS: {}", e)))
+    {
+        mqtt_options.set_transport(transport);
+    }
     mqtt_options.set_keep_alive(Duration::from_secs(5));
 
-    let (mut client, mut connection) = Client::new(mqtt_options, 10);
-
-    client.subscribe(AIR_REMOTE_TOPIC, QoS::AtMostOnce).unwrap();
-    client.subscribe(TV_STATE_TOPIC, QoS::AtLeastOnce).unwrap();
-    client.subscribe(TV_INPUT_TOPIC, QoS::AtLeastOnce).unwrap();
+    let (client, connection) = Client::new(mqtt_options, config.mqtt_request_capacity);
+    let mut client = action_reporter(client, &config);
 
-    let mut state = State {
-        tv_is_on: false,
-        dennis_is_current_input: false,
+    let mut state = State::new(&config);
+    let mut activity = Activity::new(Instant::now());
+    let reconnect_backoff = Backoff::new(
+        config.reconnect_initial_delay,
+        config.reconnect_max_delay,
+        2,
+    )
+    .with_jitter(config.reconnect_jitter);
+    let restart_budget = RestartBudget::new(
+        config.connection_restart_limit,
+        config.connection_restart_window,
+    );
+    let connection_events = spawn_connection_thread(connection, reconnect_backoff, restart_budget);
+    let mut events: Box<dyn MqttSource> = match recorder {
+        Some(recorder) => Box::new(RecordingSource::new(connection_events, recorder)),
+        None => Box::new(connection_events),
     };
 
-    println!("Starting up");
+    println!("Starting up version {}", VERSION);
+    log_startup_summary(&config);
 
-    for notification in connection.iter().enumerate() {
-        if let (_, Ok(Incoming(Publish(message)))) = notification {
-            let payload: String = String::from_utf8(message.payload.into()).unwrap();
-            match message.topic.as_str() {
-                AIR_REMOTE_TOPIC => {
-                    let event: InputEvent = serde_json::from_str(&payload).unwrap();
-                    handle_air_remote_event(&event, &state, &mut client);
-                }
-                TV_STATE_TOPIC => {
-                    if payload == "off" {
-                        state.tv_is_on = false;
-                    } else {
-                        state.tv_is_on = true;
-                    }
-                    send_passthru_flag_update(&mut client, &state);
-                    println!("State: {:?}", &state);
-                }
-                TV_INPUT_TOPIC => {
-                    if payload == "\"HDMI 1\"" {
-                        state.dennis_is_current_input = true;
-                    } else {
-                        state.dennis_is_current_input = false;
-                    }
-                    send_passthru_flag_update(&mut client, &state);
-                    println!("State: {:?}", &state);
-                }
-                _ => {
-                    println!("ERR: Message from unknown topic {:?}", message.topic);
-                }
+    let stop = run(
+        events.as_mut(),
+        &mut client,
+        &mut state,
+        &mut activity,
+        &config,
+        &SystemClock,
+        &shutdown_requested,
+    );
+    match stop {
+        Stop::Shutdown => {
+            println!("Shutting down");
+            if state.sleep_at.is_some() {
+                set_sleep_timer(&mut state, &mut client, 0, Instant::now());
+            }
+            run_shutdown_action(&config, &mut client);
+            // Nothing queued can go out without a connection anyway
+            if activity.connected {
+                flush_and_disconnect(client.inner_mut(), events.as_mut());
+            }
+        }
+        Stop::Disconnected => die("MQTT event loop stopped"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use proptest::prelude::*;
+
+    use rumqttc::{ConnectReturnCode, Packet};
+
+    use super::*;
+    use config::RepeatRule;
+    use ha_dialect::HaDialect;
+    use mqtt_sink::{MockSink, MockSource};
+    use util::TestClock;
+
+    fn remote_event() -> impl Strategy<Value = String> {
+        let with_data = (
+            prop::sample::select(vec!['A', 'B', 'C', 'K', 'U']),
+            any::<u8>(),
+        )
+            .prop_map(|(event, data)| {
+                format!(r#"{{"event":"{}","data":"0x{:02X}"}}"#, event, data)
+            });
+        let bare = prop::sample::select(vec!['N', 'O', 'o', 'W'])
+            .prop_map(|event| format!(r#"{{"event":"{}"}}"#, event));
+        prop_oneof![4 => with_data, 2 => bare, 1 => any::<String>()]
+    }
+
+    fn command() -> impl Strategy<Value = String> {
+        let names = prop::sample::select(vec![
+            "sony_command",
+            "raw_command",
+            "script",
+            "open_app",
+            "box_power",
+            "reset_box",
+            "cycle_input",
+            "guest_mode",
+            "toggle_guest_mode",
+            "sleep_timer",
+            "power",
+            "home_input",
+            "recover",
+            "all_off",
+            "resync",
+        ]);
+        let values = prop_oneof![
+            Just(String::new()),
+            any::<u64>().prop_map(|n| format!(":{}", n)),
+            any::<bool>().prop_map(|b| format!(":{}", b)),
+            "[a-zA-Z0-9_]{0,12}".prop_map(|s| format!(":{}", s)),
+        ];
+        let compact = (names, values).prop_map(|(name, value)| format!("{}{}", name, value));
+        let macro_steps = prop::collection::vec(any::<u64>(), 0..4).prop_map(|delays| {
+            let steps: Vec<_> = delays
+                .iter()
+                .map(|delay_ms| json!({ "action": "home_input", "delay_ms": delay_ms }))
+                .collect();
+            json!({ "action": "macro", "value": steps }).to_string()
+        });
+        prop_oneof![4 => compact, 1 => macro_steps, 1 => any::<String>()]
+    }
+
+    fn message() -> impl Strategy<Value = (String, String)> {
+        let to = |topic: &'static str| move |payload: String| (topic.to_string(), payload);
+        prop_oneof![
+            6 => remote_event().prop_map(to(AIR_REMOTE_TOPIC)),
+            2 => prop::sample::select(vec!["off", "on", "idle", "unavailable", "unknown", ""])
+                .prop_map(|payload| (TV_STATE_TOPIC.to_string(), payload.to_string())),
+            2 => prop::sample::select(vec![r#""HDMI 1""#, r#""HDMI 2""#, "null", "HDMI"])
+                .prop_map(|payload| (TV_INPUT_TOPIC.to_string(), payload.to_string())),
+            1 => any::<f64>().prop_map(|volume| volume.to_string()).prop_map(to(TV_VOLUME_TOPIC)),
+            1 => any::<String>().prop_map(to(TV_MUTED_TOPIC)),
+            2 => command().prop_map(to("air-remote-mediator/command")),
+            1 => (any::<String>(), any::<String>()),
+        ]
+    }
+
+    /// The message handling with a mock broker, for driving through a scenario
+    struct Harness {
+        config: Config,
+        state: State,
+        activity: Activity,
+        sink: MockSink,
+        clock: Rc<TestClock>,
+    }
+
+    impl Harness {
+        fn new(config: Config) -> Harness {
+            let clock = Rc::new(TestClock::new());
+            Harness {
+                state: State::new(&config),
+                activity: Activity::new(clock.now()),
+                sink: MockSink::default(),
+                config,
+                clock,
+            }
+        }
+
+        fn now(&self) -> Instant {
+            self.clock.now()
+        }
+
+        fn receive(&mut self, topic: &str, payload: &str) {
+            handle_mqtt_message(
+                topic,
+                payload.as_bytes(),
+                &mut self.state,
+                &mut self.activity,
+                &self.config,
+                &mut self.sink,
+                self.clock.now(),
+            );
+        }
+
+        /// Run the main loop over the events, each arriving the given time after the one
+        /// before, until they run out
+        fn run(&mut self, events: Vec<(Duration, Event)>) {
+            let mut source = MockSource::new(Rc::clone(&self.clock));
+            for (after, event) in events {
+                source.push(after, Ok(event));
+            }
+            let stop = run(
+                &mut source,
+                &mut self.sink,
+                &mut self.state,
+                &mut self.activity,
+                &self.config,
+                self.clock.as_ref(),
+                &AtomicBool::new(false),
+            );
+            assert!(matches!(stop, Stop::Disconnected));
+        }
+
+        fn tick(&mut self) {
+            tick(
+                &mut self.state,
+                &mut self.activity,
+                &self.config,
+                &mut self.sink,
+                self.clock.now(),
+            );
+        }
+
+        /// Let time pass, ticking along the way like the main loop would
+        fn wait(&mut self, duration: Duration) {
+            let deadline = self.now() + duration;
+            while self.now() < deadline {
+                self.clock.advance(TICK_INTERVAL.min(deadline - self.now()));
+                self.tick();
+            }
+        }
+
+        fn press(&mut self, event: &str) {
+            self.receive(AIR_REMOTE_TOPIC, event);
+        }
+
+        /// How many times a Home Assistant script was run
+        fn script_runs(&self, script_name: &str) -> usize {
+            let message = self
+                .config
+                .ha_dialect
+                .script(&self.config.ha_native_topic_base, script_name);
+            self.sink
+                .published
+                .iter()
+                .filter(|(topic, payload)| *topic == message.topic && *payload == message.payload)
+                .count()
+        }
+
+        /// How many times a Sony remote command was sent
+        fn command_sends(&self, command: SonyCommand) -> usize {
+            let name = to_variant_name(&command).unwrap();
+            let message = self
+                .config
+                .ha_dialect
+                .remote_command(&self.config.ha_native_topic_base, name);
+            self.sink
+                .published
+                .iter()
+                .filter(|(topic, payload)| *topic == message.topic && *payload == message.payload)
+                .count()
+        }
+    }
+
+    fn connack() -> Event {
+        Incoming(Packet::ConnAck(rumqttc::ConnAck::new(
+            ConnectReturnCode::Success,
+            false,
+        )))
+    }
+
+    fn publish(topic: &str, payload: &str, retain: bool) -> Event {
+        let mut message = rumqttc::Publish::new(topic, QoS::AtLeastOnce, payload);
+        message.retain = retain;
+        Incoming(Packet::Publish(message))
+    }
+
+    fn consumer_press(code: u8) -> String {
+        format!(r#"{{"event":"C","data":"{:#04X}"}}"#, code)
+    }
+
+    #[test]
+    fn rapid_double_power_press_toggles_once() {
+        let mut harness = Harness::new(Config::defaults());
+        harness.press(r#"{"event":"W"}"#);
+        harness.press(r#"{"event":"W"}"#);
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 1);
+    }
+
+    #[test]
+    fn disabled_consumer_code_is_swallowed_despite_mapping() {
+        let mut config = Config::defaults();
+        config.consumer_code_actions = HashMap::from([(
+            CONSUMER_CODE_PLAY_PAUSE,
+            Action::Script("movie_mode".to_string()),
+        )]);
+        config.disabled_consumer_codes = vec![CONSUMER_CODE_PLAY_PAUSE];
+        let mut harness = Harness::new(config);
+        harness.receive(TV_STATE_TOPIC, "on");
+        harness.press(&consumer_press(CONSUMER_CODE_PLAY_PAUSE));
+        assert_eq!(harness.script_runs("movie_mode"), 0);
+        assert!(harness
+            .sink
+            .published
+            .iter()
+            .all(|(topic, _)| !topic.contains("remote_command")));
+
+        harness.press(&consumer_press(CONSUMER_CODE_VOLUME_UP));
+        assert_eq!(harness.script_runs(HA_SCRIPT_TV_VOLUME_UP), 1);
+    }
+
+    #[test]
+    fn disabled_key_code_is_swallowed() {
+        let mut config = Config::defaults();
+        config.disabled_key_codes = vec![HID_KEY_ARROW_UP];
+        let mut harness = Harness::new(config);
+        harness.receive(TV_STATE_TOPIC, "on");
+        harness.press(&key_press(HID_KEY_ARROW_UP));
+        assert_eq!(harness.command_sends(SonyCommand::Up), 0);
+        harness.press(&key_press(HID_KEY_ARROW_DOWN));
+        assert_eq!(harness.command_sends(SonyCommand::Down), 1);
+    }
+
+    #[test]
+    fn disabled_power_button_does_not_toggle() {
+        let mut config = Config::defaults();
+        config.disable_power_button = true;
+        let mut harness = Harness::new(config);
+        harness.press(r#"{"event":"W"}"#);
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 0);
+    }
+
+    #[test]
+    fn unnameable_command_is_skipped() {
+        // Variants that don't serialize as a variant at all, like an untagged one, can't be named
+        #[derive(Serialize, Debug)]
+        enum Probe {
+            Home,
+            #[serde(untagged)]
+            Code(u16),
+        }
+        let config = Config::defaults();
+        let mut sink = MockSink::default();
+        send_variant_command(&mut sink, &config, &Probe::Code(0x2A));
+        assert!(sink.published.is_empty());
+
+        send_variant_command(&mut sink, &config, &Probe::Home);
+        assert_eq!(
+            sink.payloads("homeassistant_cmd/remote_command/sony_bravia"),
+            ["Home"]
+        );
+    }
+
+    #[test]
+    fn scripts_are_told_apart_under_native_dialect() {
+        let mut config = Config::defaults();
+        config.ha_dialect = HaDialect::Native;
+        let mut harness = Harness::new(config);
+        harness.press(&consumer_press(CONSUMER_CODE_VOLUME_UP));
+        assert_eq!(harness.script_runs(HA_SCRIPT_TV_VOLUME_UP), 1);
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 0);
+    }
+
+    #[test]
+    fn power_press_while_unreachable_still_toggles() {
+        let mut harness = Harness::new(Config::defaults());
+        harness.receive(TV_STATE_TOPIC, "unavailable");
+        harness.press(r#"{"event":"W"}"#);
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 1);
+    }
+
+    #[test]
+    fn turning_off_while_unreachable_is_refused() {
+        let mut harness = Harness::new(Config::defaults());
+        harness.receive(TV_STATE_TOPIC, "on");
+        harness.receive(TV_STATE_TOPIC, "unavailable");
+        harness.receive(&harness.config.command_topic.clone(), "power:false");
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 0);
+    }
+
+    #[test]
+    fn command_topic_sends_exact_remote_command() {
+        let mut harness = Harness::new(Config::defaults());
+        let command_topic = harness.config.command_topic.clone();
+        harness.run(vec![
+            (Duration::ZERO, connack()),
+            (
+                Duration::ZERO,
+                publish(
+                    &command_topic,
+                    r#"{"action": "sony_command", "value": "Home"}"#,
+                    false,
+                ),
+            ),
+        ]);
+        assert!(harness.sink.subscribed.contains(&command_topic));
+        assert_eq!(
+            harness
+                .sink
+                .payloads("homeassistant_cmd/remote_command/sony_bravia"),
+            ["Home"]
+        );
+    }
+
+    #[test]
+    fn dropped_subscribe_is_retried() {
+        let mut harness = Harness::new(Config::defaults());
+        harness.sink.subscribes_to_drop = 1;
+        harness.run(vec![(Duration::ZERO, connack())]);
+        assert!(harness
+            .sink
+            .subscribed
+            .contains(&harness.config.command_topic));
+    }
+
+    #[test]
+    fn sleep_timer_countdown_waits_for_connection() {
+        let mut harness = Harness::new(Config::defaults());
+        harness.activity.connected = true;
+        harness.receive(TV_STATE_TOPIC, "on");
+        harness.receive(&harness.config.command_topic.clone(), "sleep_timer:5");
+        harness.tick();
+        assert_eq!(harness.sink.payloads(SLEEP_TIMER_TOPIC), ["5"]);
+
+        harness.activity.connected = false;
+        harness.wait(Duration::from_secs(150));
+        assert_eq!(harness.sink.payloads(SLEEP_TIMER_TOPIC), ["5"]);
+
+        harness.activity.connected = true;
+        harness.tick();
+        assert_eq!(harness.sink.payloads(SLEEP_TIMER_TOPIC), ["5", "3"]);
+    }
+
+    #[test]
+    fn wake_topic_wakes_box() {
+        let mut config = Config::defaults();
+        config.command_topics = HashMap::from([(
+            "air-remote-mediator/wake".to_string(),
+            Action::BoxPower(true),
+        )]);
+        let mut harness = Harness::new(config);
+        harness.run(vec![
+            (Duration::ZERO, connack()),
+            (
+                Duration::ZERO,
+                publish("air-remote-mediator/wake", "", false),
+            ),
+        ]);
+        assert!(harness
+            .sink
+            .subscribed
+            .contains(&"air-remote-mediator/wake".to_string()));
+        assert_eq!(
+            harness
+                .sink
+                .payloads("homeassistant_cmd/script/wake_dennis"),
+            [""]
+        );
+    }
+
+    fn wake_topic_harness() -> Harness {
+        let mut config = Config::defaults();
+        config.command_topics = HashMap::from([
+            (
+                "air-remote-mediator/wake".to_string(),
+                Action::BoxPower(true),
+            ),
+            ("air-remote-mediator/reset".to_string(), Action::ResetBox),
+        ]);
+        config.box_wake_dedup_window = Duration::from_secs(10);
+        Harness::new(config)
+    }
+
+    #[test]
+    fn retained_wake_is_ignored() {
+        let mut harness = wake_topic_harness();
+        harness.run(vec![
+            (Duration::ZERO, connack()),
+            (
+                Duration::ZERO,
+                publish("air-remote-mediator/wake", "", true),
+            ),
+        ]);
+        assert_eq!(harness.script_runs(&harness.config.box_wake_script), 0);
+    }
+
+    #[test]
+    fn repeated_wake_is_deduplicated() {
+        let mut harness = wake_topic_harness();
+        let wake = || publish("air-remote-mediator/wake", "", false);
+        harness.run(vec![
+            (Duration::ZERO, connack()),
+            (Duration::ZERO, wake()),
+            (Duration::from_secs(5), wake()),
+            (Duration::from_secs(11), wake()),
+        ]);
+        assert_eq!(harness.script_runs(&harness.config.box_wake_script), 2);
+    }
+
+    #[test]
+    fn reset_right_after_wake_goes_through() {
+        let mut harness = wake_topic_harness();
+        harness.run(vec![
+            (Duration::ZERO, connack()),
+            (
+                Duration::ZERO,
+                publish("air-remote-mediator/wake", "", false),
+            ),
+            (
+                Duration::from_secs(1),
+                publish("air-remote-mediator/reset", "", false),
+            ),
+        ]);
+        assert_eq!(harness.script_runs(&harness.config.box_wake_script), 1);
+        assert_eq!(harness.script_runs(&harness.config.box_reset_script), 1);
+    }
+
+    #[test]
+    fn reset_after_recover_macro_goes_through() {
+        let mut harness = Harness::new(Config::defaults());
+        harness.receive(&harness.config.command_topic.clone(), "recover");
+        harness.wait(Duration::from_secs(10));
+        assert_eq!(harness.script_runs(&harness.config.box_wake_script), 1);
+        harness.receive(&harness.config.command_topic.clone(), "reset_box");
+        assert_eq!(harness.script_runs(&harness.config.box_reset_script), 1);
+    }
+
+    #[test]
+    fn sleep_timer_turns_off_like_power_button() {
+        let mut harness = Harness::new(Config::defaults());
+        harness.receive(TV_STATE_TOPIC, "on");
+        harness.state.sleep_at = Some(harness.now());
+        harness.tick();
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 1);
+        assert_eq!(harness.state.power.state(), PowerState::TurningOff);
+        assert_eq!(harness.command_sends(SonyCommand::PowerOff), 0);
+    }
+
+    #[test]
+    fn unknown_state_means_on_and_unavailable_means_unreachable() {
+        let mut harness = Harness::new(Config::defaults());
+        harness.receive(TV_INPUT_TOPIC, r#""HDMI 1""#);
+        harness.receive(TV_STATE_TOPIC, "unknown");
+        assert_eq!(tv_state(&harness.state), TvState::OnManaged);
+        assert_eq!(
+            harness.sink.payloads(AIR_REMOTE_PASSTHRU_TOPIC).last(),
+            Some(&"ON")
+        );
+
+        harness.receive(TV_STATE_TOPIC, "unavailable");
+        assert_eq!(tv_state(&harness.state), TvState::Unreachable);
+        assert_eq!(
+            harness.sink.payloads(AIR_REMOTE_PASSTHRU_TOPIC).last(),
+            Some(&"OFF")
+        );
+    }
+
+    fn keep_home_input_harness() -> Harness {
+        let mut config = Config::defaults();
+        config.keep_home_input = true;
+        config.settling_window = Duration::ZERO;
+        let mut harness = Harness::new(config);
+        harness.receive(TV_STATE_TOPIC, "on");
+        harness.receive(TV_INPUT_TOPIC, r#""HDMI 1""#);
+        harness
+    }
+
+    #[test]
+    fn drift_off_home_input_is_undone() {
+        let mut harness = keep_home_input_harness();
+        harness.receive(TV_INPUT_TOPIC, r#""HDMI 2""#);
+        assert_eq!(harness.command_sends(SonyCommand::Hdmi1), 1);
+    }
+
+    #[test]
+    fn deliberate_input_change_is_kept() {
+        let mut harness = keep_home_input_harness();
+        harness.press(&consumer_press(CONSUMER_CODE_CHANNEL));
+        assert_eq!(harness.command_sends(SonyCommand::Input), 1);
+        harness.receive(TV_INPUT_TOPIC, r#""HDMI 2""#);
+        assert_eq!(harness.command_sends(SonyCommand::Hdmi1), 0);
+    }
+
+    #[test]
+    fn drift_while_settling_is_left_alone() {
+        let mut config = Config::defaults();
+        config.keep_home_input = true;
+        config.settling_window = Duration::from_secs(10);
+        let mut harness = Harness::new(config);
+        harness.receive(TV_INPUT_TOPIC, r#""HDMI 1""#);
+        harness.receive(TV_STATE_TOPIC, "on");
+
+        harness.wait(Duration::from_secs(5));
+        harness.receive(TV_INPUT_TOPIC, r#""HDMI 2""#);
+        assert_eq!(harness.command_sends(SonyCommand::Hdmi1), 0);
+
+        harness.receive(TV_INPUT_TOPIC, r#""HDMI 1""#);
+        harness.wait(Duration::from_secs(6));
+        harness.receive(TV_INPUT_TOPIC, r#""HDMI 2""#);
+        assert_eq!(harness.command_sends(SonyCommand::Hdmi1), 1);
+    }
+
+    #[test]
+    fn power_presses_within_debounce_are_ignored() {
+        let mut config = Config::defaults();
+        config.power_debounce = Duration::from_secs(1);
+        let mut harness = Harness::new(config);
+        harness.press(r#"{"event":"W"}"#);
+        harness.wait(Duration::from_millis(500));
+        harness.receive(TV_STATE_TOPIC, "on");
+        harness.wait(Duration::from_millis(400));
+        harness.press(r#"{"event":"W"}"#);
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 1);
+
+        // Each bounced press restarts the window
+        harness.wait(Duration::from_millis(900));
+        harness.press(r#"{"event":"W"}"#);
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 1);
+        harness.wait(Duration::from_millis(1100));
+        harness.press(r#"{"event":"W"}"#);
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 2);
+    }
+
+    #[test]
+    fn single_power_press_waits_out_double_press_window() {
+        let mut config = Config::defaults();
+        config.power_double_press_action = Some(Action::ToggleGuestMode);
+        config.power_double_press_window = Duration::from_millis(500);
+        let mut harness = Harness::new(config);
+        harness.press(r#"{"event":"W"}"#);
+        harness.wait(Duration::from_millis(400));
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 0);
+        harness.wait(Duration::from_millis(100));
+        assert_eq!(harness.script_runs(HA_SCRIPT_TOGGLE_TV_AND_DENNIS), 1);
+    }
+
+    #[test]
+    fn volume_up_at_cap_does_nothing() {
+        let mut config = Config::defaults();
+        config.max_volume = Some(0.5);
+        let mut harness = Harness::new(config);
+        harness.receive(TV_STATE_TOPIC, "on");
+
+        harness.receive(TV_VOLUME_TOPIC, "0.4");
+        harness.press(&consumer_press(CONSUMER_CODE_VOLUME_UP));
+        assert_eq!(harness.script_runs(HA_SCRIPT_TV_VOLUME_UP), 1);
+
+        harness.receive(TV_VOLUME_TOPIC, "0.5");
+        harness.press(&consumer_press(CONSUMER_CODE_VOLUME_UP));
+        assert_eq!(harness.script_runs(HA_SCRIPT_TV_VOLUME_UP), 1);
+        assert_eq!(harness.sink.payloads(VOLUME_CAPPED_TOPIC).len(), 1);
+        assert_eq!(harness.script_runs(HA_SCRIPT_TV_VOLUME_DOWN), 0);
+    }
+
+    #[test]
+    fn volume_over_cap_is_turned_down() {
+        let mut config = Config::defaults();
+        config.max_volume = Some(0.5);
+        let mut harness = Harness::new(config);
+        harness.receive(TV_VOLUME_TOPIC, "0.6");
+        assert_eq!(harness.script_runs(HA_SCRIPT_TV_VOLUME_DOWN), 1);
+    }
+
+    #[test]
+    fn held_repeating_button_acts_once_per_interval() {
+        let mut config = Config::defaults();
+        config.consumer_code_repeat = HashMap::from([(
+            CONSUMER_CODE_VOLUME_UP,
+            RepeatRule {
+                repeat: true,
+                interval_ms: 200,
+            },
+        )]);
+        let mut harness = Harness::new(config);
+        harness.receive(TV_STATE_TOPIC, "on");
+        for _ in 0..10 {
+            harness.press(&consumer_press(CONSUMER_CODE_VOLUME_UP));
+            harness.wait(Duration::from_millis(50));
+        }
+        assert_eq!(harness.script_runs(HA_SCRIPT_TV_VOLUME_UP), 3);
+    }
+
+    #[test]
+    fn held_non_repeating_button_acts_once_until_released() {
+        let mut config = Config::defaults();
+        config.consumer_code_repeat = HashMap::from([(
+            CONSUMER_CODE_CHANNEL,
+            RepeatRule {
+                repeat: false,
+                interval_ms: 300,
+            },
+        )]);
+        let mut harness = Harness::new(config);
+        harness.receive(TV_STATE_TOPIC, "on");
+        for _ in 0..10 {
+            harness.press(&consumer_press(CONSUMER_CODE_CHANNEL));
+            harness.wait(Duration::from_millis(100));
+        }
+        assert_eq!(harness.command_sends(SonyCommand::Input), 1);
+
+        harness.wait(Duration::from_millis(300));
+        harness.press(&consumer_press(CONSUMER_CODE_CHANNEL));
+        assert_eq!(harness.command_sends(SonyCommand::Input), 2);
+    }
+
+    #[test]
+    fn codes_without_a_repeat_rule_pass_every_repeat() {
+        let mut harness = Harness::new(Config::defaults());
+        harness.receive(TV_STATE_TOPIC, "on");
+        for _ in 0..5 {
+            harness.press(&consumer_press(CONSUMER_CODE_VOLUME_UP));
+            harness.wait(Duration::from_millis(20));
+        }
+        assert_eq!(harness.script_runs(HA_SCRIPT_TV_VOLUME_UP), 5);
+    }
+
+    #[test]
+    fn shutdown_turns_passthru_off_by_default() {
+        let mut harness = Harness::new(Config::defaults());
+        harness.receive(TV_INPUT_TOPIC, r#""HDMI 1""#);
+        harness.receive(TV_STATE_TOPIC, "on");
+        assert_eq!(
+            harness.sink.payloads(AIR_REMOTE_PASSTHRU_TOPIC).last(),
+            Some(&"ON")
+        );
+        run_shutdown_action(&harness.config, &mut harness.sink);
+        assert_eq!(
+            harness.sink.payloads(AIR_REMOTE_PASSTHRU_TOPIC).last(),
+            Some(&"OFF")
+        );
+    }
+
+    fn key_press(code: u8) -> String {
+        format!(r#"{{"event":"K","data":"{:#04X}"}}"#, code)
+    }
+
+    /// A whole evening through the main loop: on, find something to watch, turn it up, off
+    #[test]
+    fn remote_session_end_to_end() {
+        let mut harness = Harness::new(Config::defaults());
+        let remote = |event: &str| publish(AIR_REMOTE_TOPIC, event, false);
+        let secs = Duration::from_secs;
+        let millis = Duration::from_millis;
+        harness.run(vec![
+            (Duration::ZERO, connack()),
+            (Duration::ZERO, publish(TV_STATE_TOPIC, "off", true)),
+            (Duration::ZERO, publish(TV_INPUT_TOPIC, r#""HDMI 1""#, true)),
+            (secs(5), remote(r#"{"event":"W"}"#)),
+            (secs(4), publish(TV_STATE_TOPIC, "on", false)),
+            (secs(2), remote(&key_press(HID_KEY_ARROW_DOWN))),
+            (millis(400), remote(&key_press(HID_KEY_ARROW_RIGHT))),
+            (millis(400), remote(&key_press(HID_KEY_ENTER))),
+            (secs(3), remote(&consumer_press(CONSUMER_CODE_VOLUME_UP))),
+            (
+                millis(300),
+                remote(&consumer_press(CONSUMER_CODE_VOLUME_UP)),
+            ),
+            (secs(30), remote(r#"{"event":"W"}"#)),
+            (secs(4), publish(TV_STATE_TOPIC, "off", false)),
+            (secs(5), publish(TV_STATE_TOPIC, "off", false)),
+        ]);
+        let published: Vec<_> = harness
+            .sink
+            .published
+            .iter()
+            .filter(|(topic, _)| topic != BATTERY_DISCOVERY_TOPIC)
+            .map(|(topic, payload)| (topic.as_str(), payload.as_str()))
+            .collect();
+        assert_eq!(
+            published,
+            [
+                (GUEST_MODE_TOPIC, "OFF"),
+                (SLEEP_TIMER_TOPIC, "0"),
+                (MEDIATOR_TV_STATE_TOPIC, "off"),
+                (AIR_REMOTE_PASSTHRU_TOPIC, "OFF"),
+                (AIR_REMOTE_LED_TOPIC, "off"),
+                (AIR_REMOTE_PASSTHRU_TOPIC, "OFF"),
+                (AIR_REMOTE_LED_TOPIC, "off"),
+                ("homeassistant_cmd/script/toggle_tv_and_dennis", ""),
+                (AIR_REMOTE_PASSTHRU_TOPIC, "ON"),
+                (AIR_REMOTE_LED_TOPIC, "green"),
+                (MEDIATOR_TV_STATE_TOPIC, "on_managed"),
+                ("homeassistant_cmd/remote_command/sony_bravia", "Down"),
+                ("homeassistant_cmd/remote_command/sony_bravia", "Right"),
+                ("homeassistant_cmd/remote_command/sony_bravia", "Confirm"),
+                ("homeassistant_cmd/script/tv_volume_up", ""),
+                ("homeassistant_cmd/script/tv_volume_up", ""),
+                ("homeassistant_cmd/script/toggle_tv_and_dennis", ""),
+                (AIR_REMOTE_PASSTHRU_TOPIC, "OFF"),
+                (AIR_REMOTE_LED_TOPIC, "off"),
+                (MEDIATOR_TV_STATE_TOPIC, "off"),
+                (AIR_REMOTE_PASSTHRU_TOPIC, "OFF"),
+                (AIR_REMOTE_LED_TOPIC, "off"),
+            ]
+        );
+        assert_eq!(harness.state.power.state(), PowerState::Off);
+    }
+
+    /// What the broker connection can report, in a form proptest can generate
+    #[derive(Clone, Debug)]
+    enum Traffic {
+        Message(String, String, bool),
+        Connected,
+        Failed,
+    }
+
+    fn traffic() -> impl Strategy<Value = Traffic> {
+        prop_oneof![
+            20 => (message(), prop::bool::weighted(0.1))
+                .prop_map(|((topic, payload), retain)| Traffic::Message(topic, payload, retain)),
+            1 => Just(Traffic::Connected),
+            1 => Just(Traffic::Failed),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(8))]
+
+        /// Floods the main loop with thousands of random notifications; it has to get through
+        /// all of them, quickly
+        #[test]
+        fn main_loop_soak(
+            traffic in prop::collection::vec(traffic(), 2500),
+            keep_home_input in any::<bool>(),
+        ) {
+            let mut config = Config::defaults();
+            config.command_topic = "air-remote-mediator/command".to_string();
+            config.keep_home_input = keep_home_input;
+            config.actions_topic = Some("air-remote-mediator/actions".to_string());
+            let mut harness = Harness::new(config);
+            let count = traffic.len();
+            let mut source = MockSource::new(Rc::clone(&harness.clock));
+            for traffic in traffic {
+                source.push(Duration::ZERO, match traffic {
+                    Traffic::Message(topic, payload, retain) => Ok(publish(&topic, &payload, retain)),
+                    Traffic::Connected => Ok(connack()),
+                    Traffic::Failed => Err(ConnectionError::NetworkTimeout),
+                });
+            }
+            let started_at = Instant::now();
+            let stop = run(
+                &mut source,
+                &mut harness.sink,
+                &mut harness.state,
+                &mut harness.activity,
+                &harness.config,
+                harness.clock.as_ref(),
+                &AtomicBool::new(false),
+            );
+            prop_assert!(matches!(stop, Stop::Disconnected));
+            let per_notification = started_at.elapsed() / count as u32;
+            prop_assert!(per_notification < Duration::from_millis(5), "{:?} each", per_notification);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn random_messages_never_panic(
+            messages in prop::collection::vec((0..3000u64, message()), 1..60),
+            double_press in any::<bool>(),
+            ok_long_press in any::<bool>(),
+            cap in prop::option::of(0.0..=1.0f64),
+        ) {
+            let mut config = Config::defaults();
+            config.command_topic = "air-remote-mediator/command".to_string();
+            config.power_double_press_action = double_press.then_some(Action::ToggleGuestMode);
+            config.ok_long_press = ok_long_press;
+            config.max_volume = cap;
+            config.keep_home_input = true;
+            let mut harness = Harness::new(config);
+            for (delay_ms, (topic, payload)) in messages {
+                harness.wait(Duration::from_millis(delay_ms));
+                harness.receive(&topic, &payload);
             }
         }
     }
@@ -0,0 +1,300 @@
+use std::{
+    collections::BTreeMap,
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rumqttc::{Client, ConnectionError, Event, QoS, SubscribeFilter};
+use serde_json::{json, Value};
+
+/// Everything the MQTT event loop reports, in order
+pub type Notification = Result<Event, ConnectionError>;
+
+/// Where everything the mediator hears from the broker comes from
+pub trait MqttSource {
+    /// The next notification, waiting up to `timeout` for one
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<Notification, RecvTimeoutError>;
+}
+
+impl MqttSource for Receiver<Notification> {
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<Notification, RecvTimeoutError> {
+        Receiver::recv_timeout(self, timeout)
+    }
+}
+
+/// Destination for everything the mediator publishes
+pub trait MqttSink {
+    /// Returns whether the publish was queued
+    fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: &str) -> bool;
+    /// Subscribe to all the topics in one request, so they only take one slot in the queue.
+    /// Returns whether the request was queued.
+    fn subscribe(&mut self, topics: &[(&str, QoS)]) -> bool;
+    fn unsubscribe(&mut self, topic: &str);
+    /// Per command, how many were sent and dropped, if this sink keeps count
+    fn command_stats(&self) -> Value {
+        Value::Null
+    }
+}
+
+/// Requests that don't fit in the client's queue are dropped rather than waited on, since the
+/// queue only drains while connected and waiting would stall everything else meanwhile
+impl MqttSink for Client {
+    fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: &str) -> bool {
+        let queued = Client::try_publish(self, topic, qos, retain, payload).is_ok();
+        if !queued {
+            println!("ERR: MQTT queue full, dropped publish to {}", topic);
+        }
+        queued
+    }
+
+    fn subscribe(&mut self, topics: &[(&str, QoS)]) -> bool {
+        let filters = topics
+            .iter()
+            .map(|(topic, qos)| SubscribeFilter::new(topic.to_string(), *qos));
+        let queued = Client::try_subscribe_many(self, filters).is_ok();
+        if !queued {
+            println!("ERR: MQTT queue full, dropped subscribe to {:?}", topics);
+        }
+        queued
+    }
+
+    fn unsubscribe(&mut self, topic: &str) {
+        if Client::try_unsubscribe(self, topic).is_err() {
+            println!("ERR: MQTT queue full, dropped unsubscribe from {}", topic);
+        }
+    }
+}
+
+/// Logs what would have been published, for replaying recordings without touching anything real
+pub struct DryRunSink;
+
+impl MqttSink for DryRunSink {
+    fn publish(&mut self, topic: &str, _qos: QoS, _retain: bool, payload: &str) -> bool {
+        println!("Would publish to {}: {:?}", topic, payload);
+        true
+    }
+
+    fn subscribe(&mut self, _topics: &[(&str, QoS)]) -> bool {
+        true
+    }
+
+    fn unsubscribe(&mut self, _topic: &str) {}
+}
+
+/// How one command has fared since startup
+#[derive(Default)]
+struct CommandStats {
+    sent: u64,
+    dropped: u64,
+    /// Why it last failed, and when, in seconds since the epoch
+    last_error: Option<(&'static str, u64)>,
+}
+
+/// Passes everything through, and also publishes a record of each command sent to Home
+/// Assistant on the actions topic, for an audit trail of what the mediator did. Counts of
+/// each command sent and dropped are kept for the status document.
+pub struct ActionReporter<S: MqttSink> {
+    inner: S,
+    actions_topic: Option<String>,
+    command_topics: Vec<String>,
+    stats: BTreeMap<String, CommandStats>,
+}
+
+impl<S: MqttSink> ActionReporter<S> {
+    /// `command_topics` are topic prefixes whose publishes count as commands
+    pub fn new(inner: S, actions_topic: Option<String>, command_topics: Vec<String>) -> Self {
+        ActionReporter {
+            inner,
+            actions_topic,
+            command_topics,
+            stats: BTreeMap::new(),
+        }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: MqttSink> MqttSink for ActionReporter<S> {
+    fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: &str) -> bool {
+        let queued = self.inner.publish(topic, qos, retain, payload);
+
+        if !self.command_topics.iter().any(|t| topic.starts_with(t)) {
+            return queued;
+        }
+        // Scripts are named by topic and have no payload
+        let command = if payload.is_empty() {
+            topic.rsplit('/').next().unwrap_or(topic)
+        } else {
+            payload
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default();
+        let stats = self.stats.entry(command.to_string()).or_default();
+        if queued {
+            stats.sent += 1;
+        } else {
+            stats.dropped += 1;
+            stats.last_error = Some(("MQTT queue full", timestamp));
+        }
+
+        let Some(actions_topic) = &self.actions_topic else {
+            return queued;
+        };
+        let action = json!({
+            "topic": topic,
+            "command": command,
+            "outcome": if queued { "sent" } else { "dropped" },
+            "timestamp": timestamp,
+        });
+        self.inner
+            .publish(actions_topic, QoS::AtMostOnce, false, &action.to_string());
+        queued
+    }
+
+    fn subscribe(&mut self, topics: &[(&str, QoS)]) -> bool {
+        self.inner.subscribe(topics)
+    }
+
+    fn unsubscribe(&mut self, topic: &str) {
+        self.inner.unsubscribe(topic);
+    }
+
+    fn command_stats(&self) -> Value {
+        self.stats
+            .iter()
+            .map(|(command, stats)| {
+                let last_error = stats
+                    .last_error
+                    .map(|(error, timestamp)| json!({ "error": error, "timestamp": timestamp }));
+                let summary = json!({
+                    "sent": stats.sent,
+                    "dropped": stats.dropped,
+                    "last_error": last_error,
+                });
+                (command.clone(), summary)
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+/// Keeps everything published, for tests to check
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockSink {
+    pub published: Vec<(String, String)>,
+    pub subscribed: Vec<String>,
+    /// How many publishes and subscribes to drop, as if the queue were full
+    pub publishes_to_drop: usize,
+    pub subscribes_to_drop: usize,
+}
+
+#[cfg(test)]
+impl MockSink {
+    /// Payloads published to a topic, oldest first
+    pub fn payloads(&self, topic: &str) -> Vec<&str> {
+        self.published
+            .iter()
+            .filter(|(t, _)| t == topic)
+            .map(|(_, payload)| payload.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl MqttSink for MockSink {
+    fn publish(&mut self, topic: &str, _qos: QoS, _retain: bool, payload: &str) -> bool {
+        if self.publishes_to_drop > 0 {
+            self.publishes_to_drop -= 1;
+            return false;
+        }
+        self.published
+            .push((topic.to_string(), payload.to_string()));
+        true
+    }
+
+    fn subscribe(&mut self, topics: &[(&str, QoS)]) -> bool {
+        if self.subscribes_to_drop > 0 {
+            self.subscribes_to_drop -= 1;
+            return false;
+        }
+        self.subscribed
+            .extend(topics.iter().map(|(topic, _)| topic.to_string()));
+        true
+    }
+
+    fn unsubscribe(&mut self, _topic: &str) {}
+}
+
+/// Hands out a timeline of notifications, moving a test clock along as they arrive, then reports
+/// the connection gone
+#[cfg(test)]
+pub struct MockSource {
+    clock: std::rc::Rc<crate::util::TestClock>,
+    timeline: std::collections::VecDeque<(Duration, Notification)>,
+}
+
+#[cfg(test)]
+impl MockSource {
+    pub fn new(clock: std::rc::Rc<crate::util::TestClock>) -> MockSource {
+        MockSource {
+            clock,
+            timeline: std::collections::VecDeque::new(),
+        }
+    }
+
+    /// Have `notification` arrive `after` the one queued before it
+    pub fn push(&mut self, after: Duration, notification: Notification) {
+        self.timeline.push_back((after, notification));
+    }
+}
+
+#[cfg(test)]
+impl MqttSource for MockSource {
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<Notification, RecvTimeoutError> {
+        let (after, _) = self
+            .timeline
+            .front_mut()
+            .ok_or(RecvTimeoutError::Disconnected)?;
+        if *after > timeout {
+            *after -= timeout;
+            self.clock.advance(timeout);
+            return Err(RecvTimeoutError::Timeout);
+        }
+        let (after, notification) = self.timeline.pop_front().unwrap();
+        self.clock.advance(after);
+        Ok(notification)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_commands_sent_and_dropped() {
+        let mut reporter = ActionReporter::new(
+            MockSink::default(),
+            None,
+            vec!["homeassistant_cmd/".to_string()],
+        );
+        let script = "homeassistant_cmd/script/tv_volume_up";
+        reporter.publish(script, QoS::AtLeastOnce, false, "");
+        reporter.inner_mut().publishes_to_drop = 1;
+        assert!(!reporter.publish(script, QoS::AtLeastOnce, false, ""));
+        reporter.publish("air-remote/led", QoS::AtLeastOnce, false, "green");
+
+        let stats = reporter.command_stats();
+        assert_eq!(stats.as_object().unwrap().len(), 1);
+        assert_eq!(stats["tv_volume_up"]["sent"], 1);
+        assert_eq!(stats["tv_volume_up"]["dropped"], 1);
+        assert_eq!(
+            stats["tv_volume_up"]["last_error"]["error"],
+            "MQTT queue full"
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Give up waiting for the TV to confirm a power toggle after this long
+const TRANSITION_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+    Off,
+    TurningOn,
+    On,
+    TurningOff,
+}
+
+/// Tracks TV power as reported by Home Assistant, plus any toggle we've asked for that hasn't
+/// shown up in the reports yet. Toggles are only allowed from a settled state, so a double
+/// press or a bouncy button can't turn the TV on and straight back off.
+#[derive(Debug)]
+pub struct PowerMachine {
+    state: PowerState,
+    debounce: Duration,
+    last_press_at: Option<Instant>,
+    transition_started_at: Option<Instant>,
+}
+
+impl PowerMachine {
+    pub fn new(debounce: Duration) -> PowerMachine {
+        PowerMachine {
+            state: PowerState::Off,
+            debounce,
+            last_press_at: None,
+            transition_started_at: None,
+        }
+    }
+
+    pub fn state(&self) -> PowerState {
+        self.state
+    }
+
+    /// Whether the TV was on as of the last report
+    pub fn is_on(&self) -> bool {
+        matches!(self.state, PowerState::On | PowerState::TurningOff)
+    }
+
+    /// Handle a power button press, returning whether power should actually be toggled
+    pub fn press(&mut self, now: Instant) -> bool {
+        let bounced = self
+            .last_press_at
+            .is_some_and(|pressed_at| now - pressed_at < self.debounce);
+        self.last_press_at = Some(now);
+        if bounced {
+            println!("Ignoring power press within debounce window");
+            return false;
+        }
+
+        self.expire_transition(now);
+        match self.state {
+            PowerState::Off => self.begin_transition(PowerState::TurningOn, now),
+            PowerState::On => self.begin_transition(PowerState::TurningOff, now),
+            PowerState::TurningOn | PowerState::TurningOff => {
+                println!("Ignoring power press while TV is still changing power state");
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Handle a power report from Home Assistant
+    pub fn report(&mut self, tv_is_on: bool, now: Instant) {
+        self.expire_transition(now);
+        self.state = match (self.state, tv_is_on) {
+            // Reports that predate our toggle don't end the transition
+            (PowerState::TurningOn, false) => PowerState::TurningOn,
+            (PowerState::TurningOff, true) => PowerState::TurningOff,
+            (_, true) => PowerState::On,
+            (_, false) => PowerState::Off,
+        };
+        if matches!(self.state, PowerState::On | PowerState::Off) {
+            self.transition_started_at = None;
+        }
+    }
+
+    fn begin_transition(&mut self, state: PowerState, now: Instant) {
+        self.state = state;
+        self.transition_started_at = Some(now);
+    }
+
+    /// Fall back to the last reported state if the TV never confirmed a toggle
+    fn expire_transition(&mut self, now: Instant) {
+        let expired = self
+            .transition_started_at
+            .is_some_and(|started_at| now - started_at >= TRANSITION_TIMEOUT);
+        if expired {
+            println!("TV never confirmed power change");
+            self.transition_started_at = None;
+            self.state = match self.state {
+                PowerState::TurningOn => PowerState::Off,
+                PowerState::TurningOff => PowerState::On,
+                settled => settled,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEBOUNCE: Duration = Duration::from_millis(1000);
+
+    /// A machine in the given state, as of `now`
+    fn machine_in(state: PowerState, now: Instant) -> PowerMachine {
+        let mut power = PowerMachine::new(DEBOUNCE);
+        match state {
+            PowerState::Off => {}
+            PowerState::TurningOn => assert!(power.press(now)),
+            PowerState::On => power.report(true, now),
+            PowerState::TurningOff => {
+                power.report(true, now);
+                assert!(power.press(now));
+            }
+        }
+        assert_eq!(power.state(), state);
+        power
+    }
+
+    #[test]
+    fn transition_table() {
+        use PowerState::*;
+        let start = Instant::now();
+        // Starting state, report (or None for a press), whether a press toggles, end state
+        let table = [
+            (Off, None, true, TurningOn),
+            (On, None, true, TurningOff),
+            (TurningOn, None, false, TurningOn),
+            (TurningOff, None, false, TurningOff),
+            (Off, Some(false), false, Off),
+            (Off, Some(true), false, On),
+            (On, Some(true), false, On),
+            (On, Some(false), false, Off),
+            (TurningOn, Some(false), false, TurningOn),
+            (TurningOn, Some(true), false, On),
+            (TurningOff, Some(true), false, TurningOff),
+            (TurningOff, Some(false), false, Off),
+        ];
+        for (from, report, toggles, to) in table {
+            let mut power = machine_in(from, start);
+            let later = start + DEBOUNCE * 2;
+            match report {
+                Some(tv_is_on) => power.report(tv_is_on, later),
+                None => assert_eq!(power.press(later), toggles, "press from {:?}", from),
+            }
+            assert_eq!(power.state(), to, "{:?} from {:?}", report, from);
+        }
+    }
+
+    #[test]
+    fn unconfirmed_transition_falls_back() {
+        let start = Instant::now();
+        let mut power = machine_in(PowerState::TurningOn, start);
+        power.report(false, start + TRANSITION_TIMEOUT);
+        assert_eq!(power.state(), PowerState::Off);
+
+        let mut power = machine_in(PowerState::TurningOff, start);
+        assert!(!power.press(start + TRANSITION_TIMEOUT / 2));
+        assert!(power.press(start + TRANSITION_TIMEOUT));
+        assert_eq!(power.state(), PowerState::TurningOff);
+    }
+
+    #[test]
+    fn is_on_follows_last_report() {
+        let start = Instant::now();
+        assert!(!machine_in(PowerState::Off, start).is_on());
+        assert!(!machine_in(PowerState::TurningOn, start).is_on());
+        assert!(machine_in(PowerState::On, start).is_on());
+        assert!(machine_in(PowerState::TurningOff, start).is_on());
+    }
+
+    #[test]
+    fn rapid_double_press_toggles_once() {
+        let start = Instant::now();
+        let mut power = PowerMachine::new(DEBOUNCE);
+        assert!(power.press(start));
+        assert!(!power.press(start + Duration::from_millis(200)));
+        assert_eq!(power.state(), PowerState::TurningOn);
+    }
+
+    #[test]
+    fn press_while_turning_on_is_ignored_after_debounce() {
+        let start = Instant::now();
+        let mut power = PowerMachine::new(DEBOUNCE);
+        assert!(power.press(start));
+        assert!(!power.press(start + Duration::from_secs(3)));
+        power.report(true, start + Duration::from_secs(4));
+        assert!(power.press(start + Duration::from_secs(6)));
+        assert_eq!(power.state(), PowerState::TurningOff);
+    }
+}
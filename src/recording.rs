@@ -0,0 +1,96 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+};
+
+use rumqttc::{Event::Incoming, Packet::Publish, QoS};
+use serde::{Deserialize, Serialize};
+
+use crate::mqtt_sink::{MqttSource, Notification};
+
+/// One incoming MQTT message, as a line of a recording file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordedMessage {
+    /// Milliseconds since the recording started
+    pub at_ms: u64,
+    pub topic: String,
+    pub payload: String,
+    /// Recordings from before this was kept are taken to have no retained messages
+    #[serde(default)]
+    pub retain: bool,
+}
+
+impl RecordedMessage {
+    /// The message as it would have come from the broker
+    pub fn to_publish(&self) -> rumqttc::Publish {
+        let mut message =
+            rumqttc::Publish::new(&self.topic, QoS::AtMostOnce, self.payload.as_str());
+        message.retain = self.retain;
+        message
+    }
+}
+
+pub struct Recorder {
+    file: File,
+    started_at: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> io::Result<Recorder> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder {
+            file,
+            started_at: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        let message = RecordedMessage {
+            at_ms: self.started_at.elapsed().as_millis() as u64,
+            topic: topic.to_string(),
+            payload: String::from_utf8_lossy(payload).into_owned(),
+            retain,
+        };
+        let line = serde_json::to_string(&message).unwrap();
+        if let Err(e) = writeln!(self.file, "{}", line) {
+            println!("ERR: Writing to recording: {}", e);
+        }
+    }
+}
+
+/// Passes notifications through, writing each incoming message to a recording on the way
+pub struct RecordingSource<S> {
+    pub inner: S,
+    recorder: Recorder,
+}
+
+impl<S: MqttSource> RecordingSource<S> {
+    pub fn new(inner: S, recorder: Recorder) -> RecordingSource<S> {
+        RecordingSource { inner, recorder }
+    }
+}
+
+impl<S: MqttSource> MqttSource for RecordingSource<S> {
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<Notification, RecvTimeoutError> {
+        let notification = self.inner.recv_timeout(timeout)?;
+        if let Ok(Incoming(Publish(message))) = &notification {
+            self.recorder
+                .record(&message.topic, &message.payload, message.retain);
+        }
+        Ok(notification)
+    }
+}
+
+pub fn read_recording(path: &str) -> io::Result<Vec<RecordedMessage>> {
+    let mut messages = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        messages.push(serde_json::from_str(&line)?);
+    }
+    Ok(messages)
+}
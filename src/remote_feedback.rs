@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+/// Colors the remote's indicator LED can be set to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteFeedback {
+    Off,
+    Red,
+    Green,
+    Blue,
+    Yellow,
+    White,
+}
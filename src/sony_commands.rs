@@ -1,6 +1,7 @@
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum SonyCommand {
     Num1,
     Num2,
@@ -156,3 +157,184 @@ pub enum SonyCommand {
     PartnerApp19,
     PartnerApp20,
 }
+
+
+#[cfg(test)]
+mod tests {
+    use serde_variant::to_variant_name;
+
+    use super::*;
+
+    /// Lists every command with the name HA expects for it, both as a match with no wildcard,
+    /// so a new variant doesn't compile until it's listed here too, and as an array to go
+    /// through
+    macro_rules! all_commands {
+        ($($name:ident => $expected:literal,)*) => {
+            fn expected_name(command: SonyCommand) -> &'static str {
+                match command {
+                    $(SonyCommand::$name => $expected,)*
+                }
+            }
+
+            const ALL_COMMANDS: &[SonyCommand] = &[$(SonyCommand::$name,)*];
+        };
+    }
+
+    all_commands! {
+        Num1 => "Num1",
+        Num2 => "Num2",
+        Num3 => "Num3",
+        Num4 => "Num4",
+        Num5 => "Num5",
+        Num6 => "Num6",
+        Num7 => "Num7",
+        Num8 => "Num8",
+        Num9 => "Num9",
+        Num0 => "Num0",
+        Num11 => "Num11",
+        Num12 => "Num12",
+        Enter => "Enter",
+        GGuide => "GGuide",
+        ChannelUp => "ChannelUp",
+        ChannelDown => "ChannelDown",
+        VolumeUp => "VolumeUp",
+        VolumeDown => "VolumeDown",
+        Mute => "Mute",
+        TvPower => "TvPower",
+        Audio => "Audio",
+        MediaAudioTrack => "MediaAudioTrack",
+        Tv => "Tv",
+        Input => "Input",
+        TvInput => "TvInput",
+        TvAntennaCable => "TvAntennaCable",
+        WakeUp => "WakeUp",
+        PowerOff => "PowerOff",
+        Sleep => "Sleep",
+        Right => "Right",
+        Left => "Left",
+        SleepTimer => "SleepTimer",
+        Analog2 => "Analog2",
+        TvAnalog => "TvAnalog",
+        Display => "Display",
+        Jump => "Jump",
+        PicOff => "PicOff",
+        PictureOff => "PictureOff",
+        Teletext => "Teletext",
+        Video1 => "Video1",
+        Video2 => "Video2",
+        AnalogRgb1 => "AnalogRgb1",
+        Home => "Home",
+        Exit => "Exit",
+        PictureMode => "PictureMode",
+        Confirm => "Confirm",
+        Up => "Up",
+        Down => "Down",
+        ClosedCaption => "ClosedCaption",
+        Component1 => "Component1",
+        Component2 => "Component2",
+        Wide => "Wide",
+        EPG => "EPG",
+        PAP => "PAP",
+        TenKey => "TenKey",
+        BSCS => "BSCS",
+        Ddata => "Ddata",
+        Stop => "Stop",
+        Pause => "Pause",
+        Play => "Play",
+        Rewind => "Rewind",
+        Forward => "Forward",
+        DOT => "DOT",
+        Rec => "Rec",
+        Return => "Return",
+        Blue => "Blue",
+        Red => "Red",
+        Green => "Green",
+        Yellow => "Yellow",
+        SubTitle => "SubTitle",
+        CS => "CS",
+        BS => "BS",
+        Digital => "Digital",
+        Options => "Options",
+        Media => "Media",
+        Prev => "Prev",
+        Next => "Next",
+        DPadCenter => "DpadCenter",
+        CursorUp => "CursorUp",
+        CursorDown => "CursorDown",
+        CursorLeft => "CursorLeft",
+        CursorRight => "CursorRight",
+        ShopRemoteControlForcedDynamic => "ShopRemoteControlForcedDynamic",
+        FlashPlus => "FlashPlus",
+        FlashMinus => "FlashMinus",
+        DemoMode => "DemoMode",
+        Analog => "Analog",
+        Mode3D => "Mode3D",
+        DigitalToggle => "DigitalToggle",
+        DemoSurround => "DemoSurround",
+        AD => "*AD",
+        AudioMixUp => "AudioMixUp",
+        AudioMixDown => "AudioMixDown",
+        PhotoFrame => "PhotoFrame",
+        TvRadio => "Tv_Radio",
+        SyncMenu => "SyncMenu",
+        Hdmi1 => "Hdmi1",
+        Hdmi2 => "Hdmi2",
+        Hdmi3 => "Hdmi3",
+        Hdmi4 => "Hdmi4",
+        TopMenu => "TopMenu",
+        PopUpMenu => "PopUpMenu",
+        OneTouchTimeRec => "OneTouchTimeRec",
+        OneTouchView => "OneTouchView",
+        DUX => "DUX",
+        FootballMode => "FootballMode",
+        IManual => "iManual",
+        Netflix => "Netflix",
+        Assists => "Assists",
+        FeaturedApp => "FeaturedApp",
+        FeaturedAppVOD => "FeaturedAppVOD",
+        GooglePlay => "GooglePlay",
+        ActionMenu => "ActionMenu",
+        Help => "Help",
+        TvSatellite => "TvSatellite",
+        WirelessSubwoofer => "WirelessSubwoofer",
+        AndroidMenu => "AndroidMenu",
+        RecorderMenu => "RecorderMenu",
+        STBMenu => "STBMenu",
+        MuteOn => "MuteOn",
+        MuteOff => "MuteOff",
+        AudioOutputAudioSystem => "AudioOutput_AudioSystem",
+        AudioOutputTVSpeaker => "AudioOutput_TVSpeaker",
+        AudioOutputToggle => "AudioOutput_Toggle",
+        ApplicationLauncher => "ApplicationLauncher",
+        YouTube => "YouTube",
+        PartnerApp1 => "PartnerApp1",
+        PartnerApp2 => "PartnerApp2",
+        PartnerApp3 => "PartnerApp3",
+        PartnerApp4 => "PartnerApp4",
+        PartnerApp5 => "PartnerApp5",
+        PartnerApp6 => "PartnerApp6",
+        PartnerApp7 => "PartnerApp7",
+        PartnerApp8 => "PartnerApp8",
+        PartnerApp9 => "PartnerApp9",
+        PartnerApp10 => "PartnerApp10",
+        PartnerApp11 => "PartnerApp11",
+        PartnerApp12 => "PartnerApp12",
+        PartnerApp13 => "PartnerApp13",
+        PartnerApp14 => "PartnerApp14",
+        PartnerApp15 => "PartnerApp15",
+        PartnerApp16 => "PartnerApp16",
+        PartnerApp17 => "PartnerApp17",
+        PartnerApp18 => "PartnerApp18",
+        PartnerApp19 => "PartnerApp19",
+        PartnerApp20 => "PartnerApp20",
+    }
+
+    #[test]
+    fn every_command_has_its_expected_name() {
+        for &command in ALL_COMMANDS {
+            let name = to_variant_name(&command).unwrap();
+            assert!(!name.is_empty());
+            assert_eq!(name, expected_name(command));
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+/// Overall TV situation, as far as the mediator's behavior is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TvState {
+    Off,
+    /// On the home input, where the managed box is
+    OnManaged,
+    OnOther,
+    /// Home Assistant has lost contact with the TV, so its power and input aren't known
+    Unreachable,
+}
+
+impl TvState {
+    /// Name for published state, with the managed state called whatever the user prefers
+    pub fn label(self, managed_label: &str) -> &str {
+        match self {
+            TvState::Off => "off",
+            TvState::OnManaged => managed_label,
+            TvState::OnOther => "on_other",
+            TvState::Unreachable => "unreachable",
+        }
+    }
+}
+
+impl fmt::Display for TvState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.label("on_managed"))
+    }
+}
+
+impl FromStr for TvState {
+    type Err = String;
+
+    fn from_str(label: &str) -> Result<TvState, String> {
+        [
+            TvState::Off,
+            TvState::OnManaged,
+            TvState::OnOther,
+            TvState::Unreachable,
+        ]
+        .into_iter()
+        .find(|state| state.to_string() == label)
+        .ok_or_else(|| format!("Unknown TV state {:?}", label))
+    }
+}
+
+/// How much time the TV has spent in each state, and how often it has entered each
+#[derive(Debug)]
+pub struct StateHistory {
+    current: TvState,
+    since: Instant,
+    dwell: HashMap<TvState, Duration>,
+    entries: HashMap<TvState, u64>,
+}
+
+impl StateHistory {
+    pub fn new(now: Instant) -> StateHistory {
+        StateHistory {
+            current: TvState::Off,
+            since: now,
+            dwell: HashMap::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, new_state: TvState, now: Instant) {
+        if new_state == self.current {
+            return;
+        }
+        let spent = now - self.since;
+        println!(
+            "TV state {} -> {} after {}s",
+            self.current,
+            new_state,
+            spent.as_secs()
+        );
+        *self.dwell.entry(self.current).or_default() += spent;
+        *self.entries.entry(new_state).or_default() += 1;
+        self.current = new_state;
+        self.since = now;
+    }
+
+    pub fn to_json(&self, managed_label: &str, now: Instant) -> Value {
+        let states = [
+            TvState::Off,
+            TvState::OnManaged,
+            TvState::OnOther,
+            TvState::Unreachable,
+        ];
+        let summary: serde_json::Map<String, Value> = states
+            .iter()
+            .map(|tv_state| {
+                let mut dwell = self.dwell.get(tv_state).copied().unwrap_or_default();
+                if *tv_state == self.current {
+                    dwell += now - self.since;
+                }
+                (
+                    tv_state.label(managed_label).to_string(),
+                    json!({
+                        "dwell_secs": dwell.as_secs(),
+                        "entries": self.entries.get(tv_state).copied().unwrap_or_default(),
+                    }),
+                )
+            })
+            .collect();
+        json!({
+            "current": self.current.label(managed_label),
+            "current_secs": (now - self.since).as_secs(),
+            "states": summary,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_round_trips() {
+        for state in [
+            TvState::Off,
+            TvState::OnManaged,
+            TvState::OnOther,
+            TvState::Unreachable,
+        ] {
+            assert_eq!(state.to_string().parse(), Ok(state));
+        }
+        assert!("on".parse::<TvState>().is_err());
+    }
+
+    #[test]
+    fn dwell_is_counted_per_state() {
+        let start = Instant::now();
+        let mut history = StateHistory::new(start);
+        history.update(TvState::OnManaged, start + Duration::from_secs(10));
+        history.update(TvState::OnManaged, start + Duration::from_secs(20));
+        history.update(TvState::Off, start + Duration::from_secs(70));
+        let summary = history.to_json("home", start + Duration::from_secs(75));
+        assert_eq!(summary["current"], "off");
+        assert_eq!(summary["current_secs"], 5);
+        assert_eq!(summary["states"]["off"]["dwell_secs"], 15);
+        assert_eq!(summary["states"]["home"]["dwell_secs"], 60);
+        assert_eq!(summary["states"]["home"]["entries"], 1);
+    }
+}
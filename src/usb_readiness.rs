@@ -0,0 +1,173 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsbReadinessEvent {
+    /// The line has settled on a new value
+    Changed(bool),
+    /// The line is bouncing too much to trust. Sent once, and then nothing more until the line
+    /// has held still for a whole flap window.
+    Unstable,
+}
+
+/// Debounces the USB readiness line from the remote, so Home Assistant only hears about a
+/// value once it has held for a while
+#[derive(Debug)]
+pub struct UsbReadiness {
+    settle_time: Duration,
+    flap_limit: usize,
+    flap_window: Duration,
+    stable: Option<bool>,
+    /// Most recent value from the remote, settled or not
+    reported: Option<bool>,
+    pending: Option<(bool, Instant)>,
+    /// When the value changed, over the last flap window
+    recent_changes: VecDeque<Instant>,
+    unstable: bool,
+}
+
+impl UsbReadiness {
+    pub fn new(settle_time: Duration, flap_limit: usize, flap_window: Duration) -> UsbReadiness {
+        UsbReadiness {
+            settle_time,
+            flap_limit,
+            flap_window,
+            stable: None,
+            reported: None,
+            pending: None,
+            recent_changes: VecDeque::new(),
+            unstable: false,
+        }
+    }
+
+    /// Handle a raw readiness report from the remote
+    pub fn report(&mut self, ready: bool, now: Instant) -> Option<UsbReadinessEvent> {
+        // The remote can resend the value it already sent, which changes nothing
+        if self.reported == Some(ready) {
+            return None;
+        }
+        if self.reported.is_some() {
+            self.recent_changes.push_back(now);
+        }
+        self.reported = Some(ready);
+        self.pending = Some((ready, now));
+        self.forget_old_changes(now);
+        if !self.unstable && self.recent_changes.len() > self.flap_limit {
+            self.unstable = true;
+            return Some(UsbReadinessEvent::Unstable);
+        }
+        None
+    }
+
+    /// Whether a reported value is waiting to be acted on
+    pub fn is_settling(&self) -> bool {
+        self.pending.is_some() || self.unstable
+    }
+
+    /// Check whether a pending value has now held long enough to act on
+    pub fn poll(&mut self, now: Instant) -> Option<UsbReadinessEvent> {
+        self.forget_old_changes(now);
+        if self.unstable {
+            if !self.recent_changes.is_empty() {
+                return None;
+            }
+            // Quiet for a whole flap window, so the line can be trusted again. Its value is
+            // worth repeating even if it ended up where it started.
+            self.unstable = false;
+            self.pending = None;
+            self.stable = self.reported;
+            return self.reported.map(UsbReadinessEvent::Changed);
+        }
+
+        let (ready, reported_at) = self.pending?;
+        if now - reported_at < self.settle_time {
+            return None;
+        }
+        self.pending = None;
+        if self.stable == Some(ready) {
+            return None;
+        }
+        self.stable = Some(ready);
+        Some(UsbReadinessEvent::Changed(ready))
+    }
+
+    fn forget_old_changes(&mut self, now: Instant) {
+        while self
+            .recent_changes
+            .front()
+            .is_some_and(|changed_at| now - *changed_at > self.flap_window)
+        {
+            self.recent_changes.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTLE: Duration = Duration::from_secs(2);
+    const WINDOW: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn reports_value_once_settled() {
+        let start = Instant::now();
+        let mut usb = UsbReadiness::new(SETTLE, 3, WINDOW);
+        assert_eq!(usb.report(true, start), None);
+        assert_eq!(usb.poll(start + SETTLE / 2), None);
+        assert_eq!(
+            usb.poll(start + SETTLE),
+            Some(UsbReadinessEvent::Changed(true))
+        );
+        assert_eq!(usb.poll(start + SETTLE * 2), None);
+    }
+
+    #[test]
+    fn repeated_value_is_not_a_flap() {
+        let start = Instant::now();
+        let mut usb = UsbReadiness::new(SETTLE, 3, WINDOW);
+        for i in 0..10 {
+            assert_eq!(
+                usb.report(true, start + Duration::from_millis(i * 100)),
+                None
+            );
+        }
+        assert_eq!(
+            usb.poll(start + Duration::from_secs(3)),
+            Some(UsbReadinessEvent::Changed(true))
+        );
+    }
+
+    #[test]
+    fn slow_flapping_becomes_unstable() {
+        let start = Instant::now();
+        let mut usb = UsbReadiness::new(SETTLE, 3, WINDOW);
+        let mut events = vec![];
+        // Each value holds long enough to settle, but it keeps changing
+        for i in 0..8u32 {
+            let at = start + SETTLE * 2 * i;
+            events.extend(usb.report(i % 2 == 0, at));
+            events.extend(usb.poll(at + SETTLE));
+        }
+        assert_eq!(
+            events,
+            [
+                UsbReadinessEvent::Changed(true),
+                UsbReadinessEvent::Changed(false),
+                UsbReadinessEvent::Changed(true),
+                UsbReadinessEvent::Changed(false),
+                UsbReadinessEvent::Unstable,
+            ]
+        );
+
+        // Once it has been quiet for a whole window it's trusted again
+        let last_change = start + SETTLE * 2 * 7;
+        assert_eq!(usb.poll(last_change + WINDOW), None);
+        assert_eq!(
+            usb.poll(last_change + WINDOW + SETTLE),
+            Some(UsbReadinessEvent::Changed(false))
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::{BuildHasher, Hasher},
+    process,
+    time::{Duration, Instant},
+};
+
+/// Exit with a failure, so a supervisor like systemd restarts the mediator cleanly
+pub fn die(reason: &str) -> ! {
+    println!("ERR: {}, exiting", reason);
+    process::exit(1);
+}
+
+/// Where the main loop gets the current time from
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to
+#[cfg(test)]
+pub struct TestClock {
+    now: std::cell::Cell<Instant>,
+}
+
+#[cfg(test)]
+impl TestClock {
+    pub fn new() -> TestClock {
+        TestClock {
+            now: std::cell::Cell::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+#[cfg(test)]
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+/// Exponential backoff for reconnect loops
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    factor: u32,
+    /// Each delay is randomly moved up or down by up to this fraction of itself
+    jitter: f64,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, factor: u32) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            factor,
+            jitter: 0.0,
+            next: initial,
+        }
+    }
+
+    /// Spread delays out randomly, so instances sharing a broker don't all retry at once
+    pub fn with_jitter(mut self, jitter: f64) -> Backoff {
+        self.jitter = jitter;
+        self
+    }
+
+    /// How long to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * self.factor).min(self.max);
+        delay.mul_f64(1.0 + self.jitter * (random_unit() * 2.0 - 1.0))
+    }
+
+    /// Start over from the initial delay, after a successful connection
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// Allows a few restarts, but not too many close together
+#[derive(Debug)]
+pub struct RestartBudget {
+    limit: usize,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartBudget {
+    pub fn new(limit: usize, window: Duration) -> RestartBudget {
+        RestartBudget {
+            limit,
+            window,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Counts a restart, returning whether it's within the budget
+    pub fn allow(&mut self, now: Instant) -> bool {
+        while self
+            .restarts
+            .front()
+            .is_some_and(|restarted_at| now - *restarted_at >= self.window)
+        {
+            self.restarts.pop_front();
+        }
+        self.restarts.push_back(now);
+        self.restarts.len() <= self.limit
+    }
+}
+
+/// Random number from 0.0 to 1.0, good enough for jitter. Every `RandomState` is seeded
+/// differently, so hashing nothing with a fresh one gives a new random value.
+fn random_unit() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    random as f64 / u64::MAX as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_max_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 2);
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_stays_within_fraction() {
+        let mut backoff =
+            Backoff::new(Duration::from_secs(10), Duration::from_secs(10), 2).with_jitter(0.2);
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_secs(8) && delay <= Duration::from_secs(12));
+        }
+    }
+
+    #[test]
+    fn restarts_are_limited_within_window() {
+        let start = Instant::now();
+        let mut budget = RestartBudget::new(2, Duration::from_secs(60));
+        assert!(budget.allow(start));
+        assert!(budget.allow(start + Duration::from_secs(10)));
+        assert!(!budget.allow(start + Duration::from_secs(20)));
+        // The first two have aged out by now, leaving only the refused one
+        assert!(budget.allow(start + Duration::from_secs(75)));
+        assert!(!budget.allow(start + Duration::from_secs(76)));
+    }
+}